@@ -0,0 +1,114 @@
+// blends the raymarched frame into a history buffer, reprojected with last frame's RayCamera
+
+struct TemporalUniform {
+    position: vec3<f32>,
+    forward: vec3<f32>,
+    horizontal: vec3<f32>,
+    vertical: vec3<f32>,
+    prev_position: vec3<f32>,
+    prev_forward: vec3<f32>,
+    prev_horizontal: vec3<f32>,
+    prev_vertical: vec3<f32>,
+    scale: f32,
+    prev_scale: f32,
+    aspect: f32,
+    max_distance: f32,
+    blend: f32,
+    rejection: f32,
+    reset: u32,
+    progressive: u32,
+    jitter: vec2<f32>,
+}
+
+@group(0) @binding(0) var current: texture_2d<f32>;
+@group(0) @binding(1) var history: texture_2d<f32>;
+@group(0) @binding(2) var history_sampler: sampler;
+@group(0) @binding(3) var resolved: texture_storage_2d<rgba16float, write>;
+@group(0) @binding(4) var<uniform> temporal: TemporalUniform;
+@group(0) @binding(5) var current_depth: texture_2d<f32>; //hit distances, r32float
+@group(0) @binding(6) var history_depth: texture_2d<f32>;
+
+@compute @workgroup_size(8, 8, 1)
+fn main(@builtin(global_invocation_id) id: vec3<u32>) {
+    let size = textureDimensions(resolved);
+    if id.x >= size.x || id.y >= size.y {
+        return;
+    }
+
+    let texel = vec2<i32>(id.xy);
+    let now = textureLoad(current, texel, 0);
+    let now_t = textureLoad(current_depth, texel, 0).r;
+
+    if temporal.reset != 0u {
+        textureStore(resolved, id.xy, now);
+        return;
+    }
+
+    // camera hasn't moved, so this is a plain running average of every frame so far
+    if temporal.progressive != 0u {
+        let prev = textureLoad(history, texel, 0).rgb;
+        textureStore(resolved, id.xy, vec4f(mix(prev, now.rgb, temporal.blend), 1.0));
+        return;
+    }
+
+    // rebuild the world position this pixel hit, the same way the raymarcher built its ray, jitter included.
+    // the point then lands wherever it lands in last frame's view, so its jitter doesn't matter
+    let uv = (vec2<f32>(id.xy) + 0.5 + temporal.jitter) / vec2<f32>(size) * 2.0 - 1.0;
+    let ray_d = normalize(
+        temporal.forward
+        + uv.x * temporal.scale * temporal.aspect * temporal.horizontal
+        - uv.y * temporal.scale * temporal.vertical
+    );
+    let sky = now_t >= temporal.max_distance * 0.999;
+
+    var to_point = ray_d; //sky is infinitely far, so only the direction matters
+    if !sky {
+        to_point = temporal.position + ray_d * now_t - temporal.prev_position;
+    }
+
+    // project into last frame's camera
+    let z = dot(to_point, temporal.prev_forward);
+    if z <= 0.0 {
+        textureStore(resolved, id.xy, now);
+        return;
+    }
+    let prev_ndc = vec2f(
+        dot(to_point, temporal.prev_horizontal) / (z * temporal.prev_scale * temporal.aspect),
+        dot(to_point, temporal.prev_vertical) / (z * temporal.prev_scale),
+    );
+    let prev_uv = vec2f(prev_ndc.x * 0.5 + 0.5, 0.5 - prev_ndc.y * 0.5);
+    if any(prev_uv < vec2f(0.0)) || any(prev_uv > vec2f(1.0)) {
+        textureStore(resolved, id.xy, now);
+        return;
+    }
+
+    // disocclusion, the history saw something at a different distance
+    let prev_texel = clamp(vec2<i32>(prev_uv * vec2f(size)), vec2i(0), vec2<i32>(size) - 1);
+    let prev_t = textureLoad(history_depth, prev_texel, 0).r;
+    let prev_sky = prev_t >= temporal.max_distance * 0.999;
+    if sky != prev_sky {
+        textureStore(resolved, id.xy, now);
+        return;
+    }
+    if !sky {
+        let expected = length(to_point);
+        if abs(prev_t - expected) > temporal.rejection * expected {
+            textureStore(resolved, id.xy, now);
+            return;
+        }
+    }
+
+    // clamp history to the current neighbourhood to keep ghosting down
+    var low = now.rgb;
+    var high = now.rgb;
+    for (var y = -1; y <= 1; y++) {
+        for (var x = -1; x <= 1; x++) {
+            let n = textureLoad(current, clamp(texel + vec2i(x, y), vec2i(0), vec2<i32>(size) - 1), 0).rgb;
+            low = min(low, n);
+            high = max(high, n);
+        }
+    }
+    let prev = clamp(textureSampleLevel(history, history_sampler, prev_uv, 0.0).rgb, low, high);
+
+    textureStore(resolved, id.xy, vec4f(mix(prev, now.rgb, temporal.blend), 1.0));
+}
@@ -1,6 +1,9 @@
+// the same file backs both the Material2d fragment path and the compute path (RAYMARCH_COMPUTE)
+#ifndef RAYMARCH_COMPUTE
 #import bevy_sprite::mesh2d_vertex_output::VertexOutput
 
 #import bevy_render::view::View
+#endif
 
 struct Aabb{
     min: vec3<f32>,
@@ -19,6 +22,18 @@ struct BvhNode{
 struct SdSphere{
     index: u32,
     colour: vec3<f32>,
+    emissive: f32,
+    texture: u32,
+    texture_scale: f32,
+    pattern: u32,
+    pattern_scale: f32,
+    pattern_colour: vec3<f32>,
+    rounding: f32,
+    onion: f32,
+    onion_layers: u32,
+    displacement: SdDisplacement,
+    deform: SdDeform,
+    repeat: ShapeRepeat,
     parent_index: vec2<u32>,
     radius: f32,
     transform_determinant: f32,
@@ -28,6 +43,18 @@ struct SdSphere{
 struct SdCube{
     index: u32,
     colour: vec3<f32>,
+    emissive: f32,
+    texture: u32,
+    texture_scale: f32,
+    pattern: u32,
+    pattern_scale: f32,
+    pattern_colour: vec3<f32>,
+    rounding: f32,
+    onion: f32,
+    onion_layers: u32,
+    displacement: SdDisplacement,
+    deform: SdDeform,
+    repeat: ShapeRepeat,
     parent_index: vec2<u32>,
     size: vec3<f32>,
     transform_determinant: f32,
@@ -37,6 +64,18 @@ struct SdCube{
 struct SdEllipse{
     index: u32,
     colour: vec3<f32>,
+    emissive: f32,
+    texture: u32,
+    texture_scale: f32,
+    pattern: u32,
+    pattern_scale: f32,
+    pattern_colour: vec3<f32>,
+    rounding: f32,
+    onion: f32,
+    onion_layers: u32,
+    displacement: SdDisplacement,
+    deform: SdDeform,
+    repeat: ShapeRepeat,
     parent_index: vec2<u32>,
     radii: vec3<f32>,
     transform_determinant: f32,
@@ -46,6 +85,18 @@ struct SdEllipse{
 struct SdTorus{
     index: u32,
     colour: vec3<f32>,
+    emissive: f32,
+    texture: u32,
+    texture_scale: f32,
+    pattern: u32,
+    pattern_scale: f32,
+    pattern_colour: vec3<f32>,
+    rounding: f32,
+    onion: f32,
+    onion_layers: u32,
+    displacement: SdDisplacement,
+    deform: SdDeform,
+    repeat: ShapeRepeat,
     parent_index: vec2<u32>,
     radii: vec2<f32>,
     transform_determinant: f32,
@@ -55,6 +106,18 @@ struct SdTorus{
 struct SdCylinder{
     index: u32,
     colour: vec3<f32>,
+    emissive: f32,
+    texture: u32,
+    texture_scale: f32,
+    pattern: u32,
+    pattern_scale: f32,
+    pattern_colour: vec3<f32>,
+    rounding: f32,
+    onion: f32,
+    onion_layers: u32,
+    displacement: SdDisplacement,
+    deform: SdDeform,
+    repeat: ShapeRepeat,
     parent_index: vec2<u32>,
     height: f32,
     radius: f32,
@@ -65,6 +128,18 @@ struct SdCylinder{
 struct SdCone{
     index: u32,
     colour: vec3<f32>,
+    emissive: f32,
+    texture: u32,
+    texture_scale: f32,
+    pattern: u32,
+    pattern_scale: f32,
+    pattern_colour: vec3<f32>,
+    rounding: f32,
+    onion: f32,
+    onion_layers: u32,
+    displacement: SdDisplacement,
+    deform: SdDeform,
+    repeat: ShapeRepeat,
     parent_index: vec2<u32>,
     height: f32,
     sincos: vec2<f32>,
@@ -72,6 +147,90 @@ struct SdCone{
     inverse_transform: mat4x4<f32>,
 }
 
+struct SdDisplacement{
+    kind: u32,
+    amplitude: f32,
+    frequency: f32,
+}
+
+struct SdDeform{
+    twist: f32,
+    bend: f32,
+    taper: f32,
+    shear: vec2<f32>,
+}
+
+struct ShapeRepeat{
+    spacing: vec3<f32>,
+    mode: u32, //0 single, 1 count copies per axis, 2 infinite
+    count: vec3<u32>,
+}
+
+struct Deformed{
+    q: vec3<f32>,
+    lipschitz: f32,
+}
+
+struct TerrainShape{
+    index: u32,
+    colour: vec3<f32>,
+    parent_index: vec2<u32>,
+    size: vec2<f32>,
+    height: f32,
+    range: vec2<f32>,
+    resolution: vec2<u32>,
+    height_offset: u32, //into terrain_heights
+    node_offset: u32, //into terrain_nodes, where its chunk tree's root is
+    step: f32,
+    transform_determinant: f32,
+    inverse_transform: mat4x4<f32>,
+}
+
+struct ShapeInstance{
+    shape: vec2<u32>, //the prototype's slot in the shape arrays
+    transform_determinant: f32,
+    inverse_transform: mat4x4<f32>,
+}
+
+struct SdFogVolume{
+    index: u32,
+    colour: vec3<f32>,
+    density: f32,
+    parent_index: vec2<u32>,
+    size: vec3<f32>,
+    shape: u32,
+    anisotropy: f32,
+    transform_determinant: f32,
+    inverse_transform: mat4x4<f32>,
+}
+
+struct SdSpotLight{
+    strength: f32,
+    colour: vec3<f32>,
+    inner_angle: f32,
+    outer_angle: f32,
+    range: f32,
+    translation: vec3<f32>,
+    direction: vec3<f32>,
+}
+
+struct SdAreaLight{
+    strength: f32,
+    colour: vec3<f32>,
+    shape: u32,
+    size: vec2<f32>,
+    translation: vec3<f32>,
+    right: vec3<f32>,
+    up: vec3<f32>,
+    normal: vec3<f32>,
+}
+
+struct EmissiveLight{
+    position: vec3<f32>,
+    radius: f32,
+    colour: vec3<f32>,
+}
+
 struct SdDirectionalLight{
     strength: f32,
     colour: vec3<f32>,
@@ -88,6 +247,7 @@ struct SdPositionalLight{
 struct Intersection{
     t: f32,
     colour: vec3<f32>,
+    emissive: f32,
     k: f32,
     normal: vec3<f32>,
     col_mod: vec3<f32>,
@@ -97,6 +257,15 @@ struct Intersection{
     
 }
 
+struct ShapeSurface{
+    layer: u32, //0 is untextured, otherwise one more than the layer in the texture arrays
+    texture_scale: f32,
+    pattern: u32, //0 none, 1 checker, 2 stripes, 3 value noise, 4 perlin noise, 5 cellular
+    pattern_scale: f32,
+    pattern_colour: vec3<f32>,
+    inv_transform: mat4x4<f32>,
+}
+
 struct ShadowIntersection{
     min_dist: f32,
     hit: bool,
@@ -110,11 +279,42 @@ struct RaymarchSettings{
     max_distance: f32,
     powers: vec2<f32>,
     shadow_power: f32,
-
+    bounces: u32,
+    max_steps: u32,
+    hit_epsilon: f32,
+    shadow_steps: u32,
+    normal_epsilon: f32,
+    shadow_bias: f32,
+    normal_mode: u32,
+    normal_epsilon_scale: f32,
+    sky_mode: u32,
+    sun_intensity: f32,
+    mie_g: f32,
+    fog_density: f32,
+    fog_height_density: f32,
+    fog_height_falloff: f32,
+    fog_colour: vec3<f32>,
+    fog_sky: u32,
+    fog_volume_steps: u32,
 }
 
-
+// sized from the tree depth on the cpu, the bounds checks only matter while a new pipeline is compiling
+const MAX_STACK: u32 = #{BVH_STACK_SIZE}u;
+const TERRAIN_STACK: u32 = 16u; //chunk trees are built balanced from at most 64x64 chunks
+const CONE_TILE: u32 = 8u; //pixels per side of a prepass tile, matches CONE_TILE on the rust side
+const CONE_MARGIN: f32 = 0.98; //the prepass distance is pulled in a little, the pixel rays start from there
+const BLAS_STACK: u32 = 16u; //compound trees are built balanced too
+const PI: f32 = 3.14159265;
+const BLAS_LEAF: u32 = 16u; //leaf types from here up are a part of a compound instance, x - BLAS_LEAF is its node in blas_nodes and y the instance
+
+
+#ifdef RAYMARCH_CONE
+@group(0) @binding(0) var cone_output: texture_storage_2d<r32float, write>;
+#else ifdef RAYMARCH_COMPUTE
+@group(0) @binding(0) var output: texture_storage_2d<rgba16float, write>;
+#else
 @group(0) @binding(0) var<uniform> view: View;
+#endif
 @group(2) @binding(1) var<uniform> position: vec3<f32>;
 @group(2) @binding(2) var<uniform> forward: vec3<f32>;
 @group(2) @binding(3) var<uniform> horizontal: vec3<f32>;
@@ -131,15 +331,64 @@ struct RaymarchSettings{
 @group(2) @binding(14) var<storage, read> toruses: array<SdTorus>;
 @group(2) @binding(15) var<storage, read> cylinders: array<SdCylinder>;
 @group(2) @binding(16) var<storage, read> cones: array<SdCone>;
-
+@group(2) @binding(17) var<uniform> jitter: vec2<f32>;
+@group(2) @binding(18) var<uniform> mode: u32;
+@group(2) @binding(19) var<uniform> frame: u32;
+@group(2) @binding(20) var cone_depth: texture_2d<f32>;
+@group(2) @binding(21) var<uniform> cone_prepass: u32;
+@group(2) @binding(22) var<storage, read> fog_volumes: array<SdFogVolume>;
+@group(2) @binding(23) var<storage, read> emissive_lights: array<EmissiveLight>;
+@group(2) @binding(24) var<storage, read> spot_lights: array<SdSpotLight>;
+@group(2) @binding(25) var<storage, read> area_lights: array<SdAreaLight>;
+@group(2) @binding(26) var albedo_textures: texture_2d_array<f32>;
+@group(2) @binding(27) var texture_sampler: sampler;
+@group(2) @binding(28) var normal_textures: texture_2d_array<f32>;
+@group(2) @binding(29) var<storage, read> unbounded: array<vec2<u32>>;
+@group(2) @binding(30) var<storage, read> terrains: array<TerrainShape>;
+@group(2) @binding(31) var<storage, read> terrain_heights: array<f32>;
+@group(2) @binding(32) var<storage, read> terrain_nodes: array<BvhNode>;
+@group(2) @binding(33) var<storage, read> instances: array<ShapeInstance>;
+@group(2) @binding(34) var<storage, read> blas_nodes: array<BvhNode>;
+@group(2) @binding(35) var<uniform> target_size: vec2<f32>; //pixels, the cone prepass only knows its tile count
+@group(2) @binding(36) var<storage, read> part_offsets: array<u32>; //per shape type, where the prototype parts start
+@group(2) @binding(37) var hit_depth: texture_storage_2d<r32float, write>; //render's alpha, at full precision
+
+#ifdef RAYMARCH_CONE
+@compute @workgroup_size(8, 8, 1)
+fn cone_main(@builtin(global_invocation_id) id: vec3<u32>) {
+    let tiles = textureDimensions(cone_output);
+    if id.x >= tiles.x || id.y >= tiles.y {
+        return;
+    }
+    textureStore(cone_output, id.xy, vec4f(cone_start(id.xy), 0.0, 0.0, 0.0));
+}
+#else ifdef RAYMARCH_COMPUTE
+@compute @workgroup_size(8, 8, 1)
+fn main(@builtin(global_invocation_id) id: vec3<u32>) {
+    let size = textureDimensions(output);
+    if id.x >= size.x || id.y >= size.y {
+        return;
+    }
+    let uv = (vec2<f32>(id.xy) + 0.5) / vec2<f32>(size);
+    let col = render(uv, vec2<f32>(size));
+    textureStore(output, id.xy, vec4f(col.rgb, 1.0));
+    textureStore(hit_depth, id.xy, vec4f(col.a, 0.0, 0.0, 0.0));
+}
+#else
 @fragment
 fn fragment(in: VertexOutput) -> @location(0) vec4<f32> {
-    var max_distance: f32 = 3000.0;
+    let col = render(in.uv, view.viewport.zw);
+    textureStore(hit_depth, vec2<u32>(in.position.xy), vec4f(col.a, 0.0, 0.0, 0.0));
+    return vec4f(col.rgb, 1.0);
+}
+#endif
+
+// shared by both backends, uv is 0..1 with y pointing down
+fn render(screen_uv: vec2<f32>, resolution: vec2<f32>) -> vec4<f32> {
     var fov_rad = radians(fov);
     var scale_factor = tan(fov_rad / 2.0);
 
-    var uv = (in.uv.xy * 2.0) - 1.0;
-    let resolution = view.viewport.zw;
+    var uv = ((screen_uv + jitter / resolution) * 2.0) - 1.0;
     uv.x *= resolution.x / resolution.y * scale_factor;
     uv.y *= scale_factor;
     var camera_origin = position;
@@ -147,7 +396,20 @@ fn fragment(in: VertexOutput) -> @location(0) vec4<f32> {
     var ray_direction = normalize(temp_origin - camera_origin);
     var inv_ray_direction = 1.0 / ray_direction;
 
-    var inter = initial_intersect(camera_origin, ray_direction, inv_ray_direction);
+    if mode == 1u {
+        let pixel = vec2<u32>(screen_uv * resolution);
+        var seed = hash(pixel.x ^ hash(pixel.y ^ hash(frame)));
+        return path_trace(camera_origin, ray_direction, &seed);
+    }
+
+    var start_t = 0.0;
+    if cone_prepass != 0u {
+        let tiles = vec2<i32>(textureDimensions(cone_depth));
+        let tile = clamp(vec2<i32>(screen_uv * vec2f(tiles)), vec2i(0), tiles - 1);
+        start_t = textureLoad(cone_depth, tile, 0).r;
+    }
+
+    var inter = initial_intersect(camera_origin, ray_direction, inv_ray_direction, start_t);
 
     var col = vec3f();
 
@@ -162,69 +424,529 @@ fn fragment(in: VertexOutput) -> @location(0) vec4<f32> {
 
         var shadow_id = 1 / dir_lights[0].direction;
         
-        shadow = shadow_intersect(inter.hit_pos + inter.normal * 0.2, dir_lights[0].direction, shadow_id);
+        shadow = shadow_intersect(inter.hit_pos + inter.normal * raymarch_settings.shadow_bias, dir_lights[0].direction, shadow_id, raymarch_settings.max_distance, raymarch_settings.shadow_power);
 
         var nol = max(dot(inter.normal, dir_lights[0].direction) + 0.1, 0.0) * shadow;
 
 
         var ambient = vec3f(0.02, 0.021, 0.02);
 
-        col = col * (nol + ambient);
-        col = pow(col, vec3f(0.4545));
+        let local_lights = emissive_lighting(inter.hit_pos, inter.normal) + spot_lighting(inter.hit_pos, inter.normal) + area_lighting(inter.hit_pos, inter.normal);
+        col = col * (nol + ambient + local_lights) + inter.colour * inter.emissive;
+        col = apply_fog(col, camera_origin, ray_direction, inter.t, inter.hit_pos);
+        let volume = fog_volume_march(camera_origin, ray_direction, inv_ray_direction, inter.t);
+        col = col * volume.a + volume.rgb;
         //let dist = inter.t / raymarch_settings.max_distance;
         //col = vec3f(dist, dist, dist);
-        return vec4f(col, 1.0);
+        return vec4f(col, inter.t); //alpha is the hit distance, the display turns it into depth
     }
     else{
 
+        var sun = sun_disc(ray_direction);
+
+        col = mix(sky_colour(ray_direction), dir_lights[0].colour, sun);
+        let volume = fog_volume_march(camera_origin, ray_direction, inv_ray_direction, raymarch_settings.max_distance);
+        col = col * volume.a + volume.rgb;
 
+        //let dist = inter.t / raymarch_settings.max_distance;
+        //col = vec3f(dist, dist, dist);
+        return vec4f(col, raymarch_settings.max_distance);
+        //return vec4<f32>(0.0, 0.0, 0.0, 0.0);
+    }
 
-        var testray: f32 = (ray_direction.y + 1);
+    
 
-        var lower = raymarch_settings.lower_colour;
-        var middle = raymarch_settings.middle_colour;
-        var upper = raymarch_settings.upper_colour;
+    
 
-        var mix1 = mix(lower, middle, pow(clamp((testray), 0.0, 1.0), raymarch_settings.powers.x));
-        var mix2 = mix(mix1, upper, pow(clamp(testray - 1.0, 0.0, 1.0), raymarch_settings.powers.y));
+    
+    
 
-        
-        
+}
 
+fn sky_colour(ray_direction: vec3<f32>) -> vec3<f32> {
+    if raymarch_settings.sky_mode == 1u {
+        return atmosphere(ray_direction, dir_lights[0].direction);
+    }
+    return gradient_sky(ray_direction);
+}
 
+fn gradient_sky(ray_direction: vec3<f32>) -> vec3<f32> {
+    var testray: f32 = (ray_direction.y + 1);
 
-        var sun = clamp(pow(dot(dir_lights[0].direction, ray_direction), 500.0) * 12.0, 0.0, 1.0);
+    var lower = raymarch_settings.lower_colour;
+    var middle = raymarch_settings.middle_colour;
+    var upper = raymarch_settings.upper_colour;
 
+    var mix1 = mix(lower, middle, pow(clamp((testray), 0.0, 1.0), raymarch_settings.powers.x));
+    var mix2 = mix(mix1, upper, pow(clamp(testray - 1.0, 0.0, 1.0), raymarch_settings.powers.y));
+    return mix2;
+}
 
+const EARTH_RADIUS: f32 = 6360e3;
+const ATMOSPHERE_RADIUS: f32 = 6420e3;
+const RAYLEIGH_HEIGHT: f32 = 8000.0;
+const MIE_HEIGHT: f32 = 1200.0;
+const RAYLEIGH_BETA: vec3<f32> = vec3<f32>(5.5e-6, 13.0e-6, 22.4e-6);
+const MIE_BETA: f32 = 21e-6;
+
+// distance to where a ray from inside a sphere centred on the origin leaves it
+fn sphere_exit(o: vec3<f32>, d: vec3<f32>, radius: f32) -> f32 {
+    let b = dot(o, d);
+    let c = dot(o, o) - radius * radius;
+    return -b + sqrt(max(b * b - c, 0.0));
+}
 
-        col = mix(mix2, dir_lights[0].colour, sun);
+// single scattering, 16 view samples each with 8 towards the sun
+fn atmosphere(ray_direction: vec3<f32>, sun_direction: vec3<f32>) -> vec3<f32> {
+    let origin = vec3f(0.0, EARTH_RADIUS + max(position.y, 0.0) + 1.0, 0.0);
+    let d = normalize(vec3f(ray_direction.x, max(ray_direction.y, 0.0), ray_direction.z)); //the ground is below the horizon, just carry the horizon down
+
+    let ray_length = sphere_exit(origin, d, ATMOSPHERE_RADIUS);
+    let step = ray_length / 16.0;
+
+    let mu = dot(d, sun_direction);
+    let phase_r = 3.0 / (16.0 * PI) * (1.0 + mu * mu);
+    let g = raymarch_settings.mie_g;
+    let phase_m = 3.0 / (8.0 * PI) * ((1.0 - g * g) * (1.0 + mu * mu)) / ((2.0 + g * g) * pow(1.0 + g * g - 2.0 * g * mu, 1.5));
+
+    var sum_r = vec3f(0.0);
+    var sum_m = vec3f(0.0);
+    var depth_r = 0.0;
+    var depth_m = 0.0;
+
+    for (var i = 0u; i < 16u; i++) {
+        let p = origin + d * (f32(i) + 0.5) * step;
+        let height = length(p) - EARTH_RADIUS;
+        let hr = exp(-height / RAYLEIGH_HEIGHT) * step;
+        let hm = exp(-height / MIE_HEIGHT) * step;
+        depth_r += hr;
+        depth_m += hm;
+
+        let light_length = sphere_exit(p, sun_direction, ATMOSPHERE_RADIUS);
+        let light_step = light_length / 8.0;
+        var light_r = 0.0;
+        var light_m = 0.0;
+        var blocked = false;
+        for (var j = 0u; j < 8u; j++) {
+            let lp = p + sun_direction * (f32(j) + 0.5) * light_step;
+            let light_height = length(lp) - EARTH_RADIUS;
+            if light_height < 0.0 {
+                blocked = true;
+                break;
+            }
+            light_r += exp(-light_height / RAYLEIGH_HEIGHT) * light_step;
+            light_m += exp(-light_height / MIE_HEIGHT) * light_step;
+        }
+        if blocked {
+            continue;
+        }
 
-        col = pow(col, vec3f(0.4545));
-        //let dist = inter.t / raymarch_settings.max_distance;
-        //col = vec3f(dist, dist, dist);
-        return vec4f(col, 1.0);
-        //return vec4<f32>(0.0, 0.0, 0.0, 0.0);
+        let attenuation = exp(-(RAYLEIGH_BETA * (depth_r + light_r) + MIE_BETA * 1.1 * (depth_m + light_m)));
+        sum_r += attenuation * hr;
+        sum_m += attenuation * hm;
     }
 
-    
+    return raymarch_settings.sun_intensity * (sum_r * RAYLEIGH_BETA * phase_r + sum_m * MIE_BETA * phase_m);
+}
 
-    
+// walks every fog volume the ray crosses before t_max in fixed steps, rgb is the light scattered towards
+// the camera and a is how much of what's behind still gets through. each sample asks shadow_intersect
+// whether the light reaches it, which is what carves the shafts
+fn fog_volume_march(ray_o: vec3<f32>, ray_d: vec3<f32>, ray_id: vec3<f32>, t_max: f32) -> vec4<f32> {
+    var inscatter = vec3f(0.0);
+    var transmittance = 1.0;
+    let steps = max(raymarch_settings.fog_volume_steps, 1u);
 
-    
-    
+    var stack: array<u32, MAX_STACK>;
+    var stackPtr: i32 = 0;
+    let stack_size = i32(MAX_STACK);
+    stack[stackPtr] = root_index;
+    stackPtr = stackPtr + 1;
+
+    loop {
+        if (stackPtr == 0 || transmittance < 0.01) {
+            break;
+        }
+        stackPtr = stackPtr - 1;
+        let currentNode: BvhNode = nodes[stack[stackPtr]];
 
+        let dists = intersect_aabb_dist(ray_o, ray_id, currentNode.aabb, t_max);
+        if (dists.y <= 0.0 || dists.x > t_max) {
+            continue;
+        }
+
+        let child1 = currentNode.child1;
+        let child2 = currentNode.child2;
+
+        if (child1.x == 7u) {
+            let volume = fog_volumes[child1.y];
+            let start = max(dists.x, 0.0);
+            let end = min(dists.x + dists.y, t_max);
+            let step = (end - start) / f32(steps);
+
+            for (var i = 0u; i < steps; i++) {
+                let p = ray_o + ray_d * (start + (f32(i) + 0.5) * step);
+                let local = opTransform(p, volume.inverse_transform);
+                var dist = SdfCube(local, volume.size);
+                if volume.shape == 1u {
+                    dist = SdfSphere(local, volume.size.x);
+                }
+                if dist * volume.transform_determinant > 0.0 {
+                    continue;
+                }
+
+                let extinction = volume.density * step;
+                var light = vec3f(0.0);
+                for (var l = 0u; l < arrayLength(&dir_lights); l++) {
+                    let dir_light = dir_lights[l];
+                    let shadow = shadow_intersect(p, dir_light.direction, 1.0 / dir_light.direction, raymarch_settings.max_distance, raymarch_settings.shadow_power);
+                    light += dir_light.colour * dir_light.strength * shadow * henyey_greenstein(dot(ray_d, dir_light.direction), volume.anisotropy);
+                }
+                inscatter += transmittance * extinction * volume.colour * light;
+                transmittance *= exp(-extinction);
+            }
+        } else if (child1.x == 0) {
+            if (stackPtr < stack_size) {
+                stack[stackPtr] = child2.y;
+                stackPtr = stackPtr + 1;
+            }
+            if (stackPtr < stack_size) {
+                stack[stackPtr] = child1.y;
+                stackPtr = stackPtr + 1;
+            }
+        }
+    }
+
+    return vec4f(inscatter, transmittance);
+}
+
+fn henyey_greenstein(cos_theta: f32, g: f32) -> f32 {
+    let denom = 1.0 + g * g - 2.0 * g * cos_theta;
+    return (1.0 - g * g) / (4.0 * PI * pow(denom, 1.5));
+}
+
+// every emissive shape flagged with SdEmissiveLight as a spherical light, falling off with the solid angle it covers
+fn emissive_lighting(p: vec3<f32>, n: vec3<f32>) -> vec3<f32> {
+    var light = vec3f(0.0);
+    let origin = p + n * raymarch_settings.shadow_bias;
+    for (var i = 0u; i < arrayLength(&emissive_lights); i++) {
+        let emitter = emissive_lights[i];
+        let to_light = emitter.position - origin;
+        let dist = length(to_light);
+        if dist <= emitter.radius {
+            continue; //this is the emitter itself
+        }
+        let l = to_light / dist;
+        let nol = dot(n, l);
+        if nol <= 0.0 {
+            continue;
+        }
+        let shadow = shadow_intersect(origin, l, 1.0 / l, max(dist - emitter.radius * 1.5, 0.0), emitter.radius / dist); //past the padding on its aabb
+        let solid_angle = (emitter.radius * emitter.radius) / (dist * dist);
+        light += emitter.colour * nol * solid_angle * shadow;
+    }
+    return light;
+}
+
+// smooth cone edge between the two angles and a windowed inverse square falloff that reaches zero at range,
+// shadowed the same soft way as the sun
+fn spot_lighting(p: vec3<f32>, n: vec3<f32>) -> vec3<f32> {
+    var light = vec3f(0.0);
+    let origin = p + n * raymarch_settings.shadow_bias;
+    for (var i = 0u; i < arrayLength(&spot_lights); i++) {
+        let spot = spot_lights[i];
+        let to_light = spot.translation - origin;
+        let dist = length(to_light);
+        if dist >= spot.range {
+            continue;
+        }
+        let l = to_light / dist;
+        let nol = dot(n, l);
+        if nol <= 0.0 {
+            continue;
+        }
+
+        let cos_outer = cos(radians(spot.outer_angle));
+        let cos_inner = cos(radians(min(spot.inner_angle, spot.outer_angle)));
+        let cone = smoothstep(cos_outer, max(cos_inner, cos_outer + 1e-4), dot(-l, spot.direction));
+        if cone <= 0.0 {
+            continue;
+        }
+
+        let window = pow(clamp(1.0 - pow(dist / spot.range, 4.0), 0.0, 1.0), 2.0);
+        let falloff = window / max(dist * dist, 1e-4);
+
+        let shadow = shadow_intersect(origin, l, 1.0 / l, dist, raymarch_settings.shadow_power);
+        light += spot.colour * spot.strength * nol * cone * falloff * shadow;
+    }
+    return light;
+}
+
+// rectangles light from their nearest point and spheres from their centre, both falling off with the solid angle
+// they cover. the shadow cone is as wide as the light seen from p, so bigger or closer lights give wider penumbrae
+fn area_lighting(p: vec3<f32>, n: vec3<f32>) -> vec3<f32> {
+    var light = vec3f(0.0);
+    let origin = p + n * raymarch_settings.shadow_bias;
+    for (var i = 0u; i < arrayLength(&area_lights); i++) {
+        let area = area_lights[i];
+
+        var nearest = area.translation;
+        var radius = area.size.x;
+        var facing = 1.0;
+        if area.shape == 0u {
+            let offset = origin - area.translation;
+            let half_size = area.size * 0.5;
+            nearest += area.right * clamp(dot(offset, area.right), -half_size.x, half_size.x)
+                + area.up * clamp(dot(offset, area.up), -half_size.y, half_size.y);
+            radius = sqrt(area.size.x * area.size.y / PI); //disc of the same area
+        }
+
+        let to_light = nearest - origin;
+        let dist = length(to_light);
+        if dist <= 1e-4 {
+            continue;
+        }
+        let l = to_light / dist;
+        let nol = dot(n, l);
+        if nol <= 0.0 {
+            continue;
+        }
+        if area.shape == 0u {
+            facing = dot(-l, area.normal); //one sided
+            if facing <= 0.0 {
+                continue;
+            }
+        }
+
+        let solid_angle = radius * radius / (dist * dist + radius * radius);
+        let shadow = shadow_intersect(origin, l, 1.0 / l, dist, radius / dist);
+        light += area.colour * area.strength * nol * facing * solid_angle * shadow;
+    }
+    return light;
+}
+
+// distance fog times height fog, the height part integrates exp(-falloff * y) along the ray in closed form
+fn apply_fog(col: vec3<f32>, ray_o: vec3<f32>, ray_d: vec3<f32>, t: f32, hit_pos: vec3<f32>) -> vec3<f32> {
+    let falloff = max(raymarch_settings.fog_height_falloff, 1e-4);
+    let dy = hit_pos.y - ray_o.y;
+    var height = t * exp(-falloff * ray_o.y);
+    if abs(dy) > 1e-4 {
+        height = t * (exp(-falloff * ray_o.y) - exp(-falloff * hit_pos.y)) / (falloff * dy);
+    }
+    let optical_depth = raymarch_settings.fog_density * t + raymarch_settings.fog_height_density * height;
+    let fog = 1.0 - exp(-optical_depth);
+
+    var fog_colour = raymarch_settings.fog_colour;
+    if raymarch_settings.fog_sky != 0u {
+        fog_colour = sky_colour(ray_d);
+    }
+    return mix(col, fog_colour, fog);
+}
+
+fn sun_disc(ray_direction: vec3<f32>) -> f32 {
+    return clamp(pow(dot(dir_lights[0].direction, ray_direction), 500.0) * 12.0, 0.0, 1.0);
+}
+
+// pcg hash, plenty for per pixel random sequences
+fn hash(v: u32) -> u32 {
+    let state = v * 747796405u + 2891336453u;
+    let word = ((state >> ((state >> 28u) + 4u)) ^ state) * 277803737u;
+    return (word >> 22u) ^ word;
+}
+
+fn rand(seed: ptr<function, u32>) -> f32 {
+    *seed = hash(*seed);
+    return f32(*seed) / 4294967295.0;
+}
+
+fn cosine_hemisphere(n: vec3<f32>, seed: ptr<function, u32>) -> vec3<f32> {
+    let r1 = rand(seed);
+    let r2 = rand(seed);
+    let phi = 6.2831853 * r1;
+    let r = sqrt(r2);
+
+    let up = select(vec3f(1.0, 0.0, 0.0), vec3f(0.0, 1.0, 0.0), abs(n.y) < 0.999);
+    let tangent = normalize(cross(up, n));
+    let bitangent = cross(n, tangent);
+    return normalize(tangent * cos(phi) * r + bitangent * sin(phi) * r + n * sqrt(1.0 - r2));
+}
+
+// monte carlo path tracer over the same bvh, one sample per pixel per frame, the temporal pass averages them
+fn path_trace(ray_o: vec3<f32>, ray_d: vec3<f32>, seed: ptr<function, u32>) -> vec4<f32> {
+    var o = ray_o;
+    var d = ray_d;
+    var throughput = vec3f(1.0);
+    var radiance = vec3f(0.0);
+    var first_t = raymarch_settings.max_distance;
+
+    for (var bounce = 0u; bounce <= raymarch_settings.bounces; bounce++) {
+        let inter = initial_intersect(o, d, 1.0 / d, 0.0);
+        if bounce == 0u {
+            first_t = inter.t;
+        }
+
+        if !inter.hit {
+            // the sun is sampled directly below, so only camera rays get to see its disc
+            radiance += throughput * sky_colour(d);
+            if bounce == 0u {
+                radiance = mix(radiance, dir_lights[0].colour, sun_disc(d));
+            }
+            break;
+        }
+
+        let n = inter.normal;
+        radiance += throughput * inter.colour * inter.emissive;
+
+        // dielectric fresnel picks between a mirror bounce and a diffuse one
+        let fresnel = 0.04 + 0.96 * pow(1.0 - max(dot(n, -d), 0.0), 5.0);
+
+        // next event estimation for the directional lights, only the diffuse lobe's share of the light is direct.
+        // the lambert brdf is albedo / pi, every light is sampled once and deterministically so there's no pdf to divide by
+        let diffuse = throughput * inter.colour * (1.0 - fresnel) / PI;
+        let p = inter.hit_pos + n * (0.01 + 0.002 * inter.t);
+        for (var i = 0u; i < arrayLength(&dir_lights); i++) {
+            let light = dir_lights[i];
+            let nol = dot(n, light.direction);
+            if nol > 0.0 {
+                let shadow = shadow_intersect(p, light.direction, 1.0 / light.direction, raymarch_settings.max_distance, raymarch_settings.shadow_power);
+                radiance += diffuse * light.colour * light.strength * nol * shadow;
+            }
+        }
+        radiance += diffuse * (spot_lighting(inter.hit_pos, n) + area_lighting(inter.hit_pos, n));
+
+        if rand(seed) < fresnel {
+            d = reflect(d, n);
+        } else {
+            d = cosine_hemisphere(n, seed);
+            throughput *= inter.colour;
+        }
+        o = p;
+
+        // russian roulette once the path has had a couple of bounces
+        if bounce > 1u {
+            let survive = clamp(max(throughput.x, max(throughput.y, throughput.z)), 0.05, 1.0);
+            if rand(seed) > survive {
+                break;
+            }
+            throughput /= survive;
+        }
+    }
+
+    return vec4f(radiance, first_t); //linear hdr like the direct path, the camera tonemaps it
+}
+
+// the tile's corner rays bound a cone around its centre ray, march that cone through every leaf it touches
+// and keep the nearest distance where a surface could be inside it
+fn cone_start(tile: vec2<u32>) -> f32 {
+    let scale_factor = tan(radians(fov) / 2.0);
+    let aspect = target_size.x / target_size.y;
+
+    // the same mapping as render, from the middle of the tile's pixels. the last row and column of tiles hang
+    // over the edge of the target when it isn't a multiple of CONE_TILE, but their pixels are still within reach
+    let tile_px = f32(CONE_TILE);
+    var uv = ((vec2<f32>(tile) * tile_px + tile_px * 0.5) / target_size) * 2.0 - 1.0;
+    uv.x *= aspect * scale_factor;
+    uv.y *= scale_factor;
+    let ray_o = position;
+    let ray_d = normalize(forward + (uv.x * horizontal) + (-uv.y * vertical));
+    let ray_id = 1.0 / ray_d;
+
+    // half the tile diagonal on the image plane, over the distance to it, is the cone's slope. a pixel's jitter
+    // moves its ray up to half a pixel, so the tile is widened by that much on each side
+    let pixel = 2.0 * scale_factor / target_size.y;
+    let half_tile = vec2f(tile_px * 0.5 + 0.5) * pixel;
+    let plane_distance = length(vec3f(uv, 1.0));
+    let slope = length(half_tile) / plane_distance;
+
+    var best = raymarch_settings.max_distance;
+
+    var stack: array<u32, MAX_STACK>;
+    var stackPtr: i32 = 0;
+    let stack_size = i32(MAX_STACK);
+    stack[stackPtr] = root_index;
+    stackPtr = stackPtr + 1;
+
+    loop {
+        if (stackPtr == 0) {
+            break;
+        }
+        stackPtr = stackPtr - 1;
+        let currentNode: BvhNode = nodes[stack[stackPtr]];
+
+        let dists = intersect_aabb_dist(ray_o, ray_id, cone_aabb(currentNode.aabb, ray_o, slope), best);
+        if (dists.y <= 0.0 || dists.x > best) {
+            continue;
+        }
+
+        let child1 = currentNode.child1;
+        let child2 = currentNode.child2;
+
+        if (child1.x == 7u) {
+            continue; //fog volume, only fog_volume_march looks at these
+        }
+        if (is_compound(child1)) {
+            best = compound_cone(child1.y, ray_o, ray_d, dists, slope, best);
+        } else if (child1.x != 0) {
+            best = cone_march(child1, ray_o, ray_d, dists, slope, best);
+        } else {
+            if (stackPtr < stack_size) {
+                stack[stackPtr] = child2.y;
+                stackPtr = stackPtr + 1;
+            }
+            if (stackPtr < stack_size) {
+                stack[stackPtr] = child1.y;
+                stackPtr = stackPtr + 1;
+            }
+        }
+    }
+
+    for (var i = 0u; i < arrayLength(&unbounded); i++) {
+        best = cone_march(unbounded[i], ray_o, ray_d, vec2f(0.0, best), slope, best);
+    }
+
+    return max(best * CONE_MARGIN, 0.0);
+}
+
+fn cone_march(idx: vec2<u32>, ray_o: vec3<f32>, ray_d: vec3<f32>, dists: vec2<f32>, slope: f32, best: f32) -> f32 {
+    var t = dists.x;
+    let end = dists.x + dists.y;
+    for (var i = 0u; i < raymarch_settings.max_steps && t < end && t < best; i++) {
+        let dist = map(ray_o + ray_d * t, idx);
+        let radius = slope * t;
+        if (dist < radius + raymarch_settings.hit_epsilon * t) {
+            return min(best, t - radius); //step back by the cone width, the pixel rays inside can hit that much sooner
+        }
+        // the cone keeps widening while it steps, so only go as far as the surface can't reach the cone's edge
+        t = t + max(dist - radius, raymarch_settings.hit_epsilon * t) / (1.0 + slope);
+    }
+    if (t < end && t < best) {
+        return t; //ran out of steps before clearing the box, nothing nearer than here was ruled out
+    }
+    return best;
+}
+
+// grows a box by the cone's radius at its far side, so a centre ray test stands in for the whole cone
+fn cone_aabb(aabb: Aabb, ray_o: vec3<f32>, slope: f32) -> Aabb {
+    let centre = (aabb.min + aabb.max) * 0.5;
+    let reach = length(centre - ray_o) + length(aabb.max - centre);
+    var grown: Aabb;
+    grown.min = aabb.min - vec3f(slope * reach);
+    grown.max = aabb.max + vec3f(slope * reach);
+    return grown;
 }
 
 fn initial_intersect(
     ray_o: vec3<f32>, 
     ray_d: vec3<f32>,
     ray_id: vec3<f32>,
+    start_t: f32, //nothing is closer than this, from the cone prepass
 ) -> Intersection{
     let max_distance = raymarch_settings.max_distance;
     var inter: Intersection;
     inter.hit = false;
     inter.t = max_distance;
     inter.colour = vec3<f32>(0.0, 0.0, 0.0);
+    inter.emissive = 0.0;
     inter.k = 0.0;
     inter.normal = vec3<f32>(0.0, 0.0, 0.0);
     inter.col_mod = vec3<f32>(1.0, 1.0, 1.0);
@@ -232,8 +954,9 @@ fn initial_intersect(
 
     let default_idx = vec2<u32>(0, 0);
     
-    var stack: array<u32, 20>;
+    var stack: array<u32, MAX_STACK>;
     var stackPtr: i32 = 0;
+    let stack_size = i32(MAX_STACK);
 
     // Push the root index onto the stack
     stack[stackPtr] = root_index;
@@ -260,8 +983,19 @@ fn initial_intersect(
         var child1 = currentNode.child1;
         var child2 = currentNode.child2;
 
+        if (child1.x == 7u) {
+            continue; //fog volume, only fog_volume_march looks at these
+        }
         if (child1.x != 0) {
-            inter = raymarch(child1, ray_o, ray_d, current_dists, inter);
+            let start = max(current_dists.x, start_t);
+            let leaf_dists = vec2f(start, current_dists.x + current_dists.y - start);
+            if (leaf_dists.y > 0.0 && child1.x == 8u) {
+                inter = terrain_intersect(child1.y, ray_o, ray_d, leaf_dists, inter);
+            } else if (leaf_dists.y > 0.0 && is_compound(child1)) {
+                inter = compound_intersect(child1.y, ray_o, ray_d, leaf_dists, inter);
+            } else if (leaf_dists.y > 0.0) {
+                inter = raymarch(child1, ray_o, ray_d, leaf_dists, inter);
+            }
 
         }else{
 
@@ -269,20 +1003,20 @@ fn initial_intersect(
             var dists2 = intersect_aabb_dist(ray_o, ray_id, nodes[child2.y].aabb, inter.t);
 
             if (dists1.x < dists2.x) {
-                if (dists2.y > 0.0 && dists2.x < inter.t){
+                if (dists2.y > 0.0 && dists2.x < inter.t && stackPtr < stack_size){
                     stack[stackPtr] = currentNode.child2.y;
                     stackPtr = stackPtr + 1;
                 }
-                if (dists1.y > 0.0 && dists1.x < inter.t){
+                if (dists1.y > 0.0 && dists1.x < inter.t && stackPtr < stack_size){
                     stack[stackPtr] = currentNode.child1.y;
                     stackPtr = stackPtr + 1;
                 }
             } else {
-                if (dists1.y > 0.0 && dists1.x < inter.t){
+                if (dists1.y > 0.0 && dists1.x < inter.t && stackPtr < stack_size){
                     stack[stackPtr] = currentNode.child1.y;
                     stackPtr = stackPtr + 1;
                 }
-                if (dists2.y > 0.0 && dists2.x < inter.t){
+                if (dists2.y > 0.0 && dists2.x < inter.t && stackPtr < stack_size){
                     stack[stackPtr] = currentNode.child2.y;
                     stackPtr = stackPtr + 1;
                 }
@@ -293,9 +1027,265 @@ fn initial_intersect(
 
     }
 
+    // infinitely repeated shapes have no bounds to put in the tree, so every ray marches them up to whatever it hit
+    for (var i = 0u; i < arrayLength(&unbounded); i++) {
+        if (inter.t > start_t) {
+            inter = raymarch(unbounded[i], ray_o, ray_d, vec2f(start_t, inter.t - start_t), inter);
+        }
+    }
+
+    return inter;
+}
+
+
+// walks a terrain's own tree of chunks in its local space, where t is the same as along the world ray since the
+// direction isn't renormalized, and only marches the stretches of the ray inside chunks it passes through
+fn terrain_intersect(terrain_idx: u32, ray_o: vec3<f32>, ray_d: vec3<f32>, dists: vec2<f32>, intersect: Intersection) -> Intersection {
+    var inter = intersect;
+    let terrain = terrains[terrain_idx];
+    let local_o = opTransform(ray_o, terrain.inverse_transform);
+    let local_id = 1.0 / (terrain.inverse_transform * vec4f(ray_d, 0.0)).xyz;
+
+    var stack: array<u32, TERRAIN_STACK>;
+    var stackPtr: i32 = 0;
+    stack[stackPtr] = terrain.node_offset;
+    stackPtr = stackPtr + 1;
+
+    loop {
+        if (stackPtr == 0) {
+            break;
+        }
+        stackPtr = stackPtr - 1;
+        let node = terrain_nodes[stack[stackPtr]];
+
+        let chunk_dists = intersect_aabb_dist(local_o, local_id, node.aabb, inter.t);
+        if (chunk_dists.y <= 0.0 || chunk_dists.x > inter.t) {
+            continue;
+        }
+
+        if (node.child1.x != 0) {
+            let start = max(chunk_dists.x, dists.x);
+            let end = min(chunk_dists.x + chunk_dists.y, dists.x + dists.y);
+            if (end > start) {
+                inter = raymarch(vec2<u32>(8u, terrain_idx), ray_o, ray_d, vec2f(start, end - start), inter);
+            }
+        } else {
+            let child1 = terrain.node_offset + node.child1.y;
+            let child2 = terrain.node_offset + node.child2.y;
+            let dists1 = intersect_aabb_dist(local_o, local_id, terrain_nodes[child1].aabb, inter.t);
+            let dists2 = intersect_aabb_dist(local_o, local_id, terrain_nodes[child2].aabb, inter.t);
+            var near = child1;
+            var far = child2;
+            if (dists2.x < dists1.x) {
+                near = child2;
+                far = child1;
+            }
+            if (stackPtr < i32(TERRAIN_STACK)) {
+                stack[stackPtr] = far;
+                stackPtr = stackPtr + 1;
+            }
+            if (stackPtr < i32(TERRAIN_STACK)) {
+                stack[stackPtr] = near;
+                stackPtr = stackPtr + 1;
+            }
+        }
+    }
+
+    return inter;
+}
+
+fn terrain_shadow(terrain_idx: u32, ray_o: vec3<f32>, ray_d: vec3<f32>, dists: vec2<f32>, penumbra: f32) -> f32 {
+    var shadow_res = 1.0;
+    let terrain = terrains[terrain_idx];
+    let local_o = opTransform(ray_o, terrain.inverse_transform);
+    let local_id = 1.0 / (terrain.inverse_transform * vec4f(ray_d, 0.0)).xyz;
+
+    var stack: array<u32, TERRAIN_STACK>;
+    var stackPtr: i32 = 0;
+    stack[stackPtr] = terrain.node_offset;
+    stackPtr = stackPtr + 1;
+
+    loop {
+        if (stackPtr == 0 || shadow_res < 0.0001) {
+            break;
+        }
+        stackPtr = stackPtr - 1;
+        let node = terrain_nodes[stack[stackPtr]];
+
+        let chunk_dists = intersect_aabb_dist(local_o, local_id, node.aabb, dists.x + dists.y);
+        if (chunk_dists.y <= 0.0) {
+            continue;
+        }
+
+        if (node.child1.x != 0) {
+            let start = max(chunk_dists.x, dists.x);
+            let end = min(chunk_dists.x + chunk_dists.y, dists.x + dists.y);
+            if (end > start) {
+                shadow_res = min(shadow_res, soft_shadow_raymarch(vec2<u32>(8u, terrain_idx), ray_o, ray_d, vec2f(start, end - start), penumbra));
+            }
+        } else {
+            if (stackPtr < i32(TERRAIN_STACK)) {
+                stack[stackPtr] = terrain.node_offset + node.child2.y;
+                stackPtr = stackPtr + 1;
+            }
+            if (stackPtr < i32(TERRAIN_STACK)) {
+                stack[stackPtr] = terrain.node_offset + node.child1.y;
+                stackPtr = stackPtr + 1;
+            }
+        }
+    }
+
+    return shadow_res;
+}
+
+// the bottom level tree of a compound instance sits in the prototype's space, so the ray goes in through the
+// instance's inverse transform like a terrain's, and each part it reaches is marched as BLAS_LEAF + its node
+fn compound_intersect(instance_idx: u32, ray_o: vec3<f32>, ray_d: vec3<f32>, dists: vec2<f32>, intersect: Intersection) -> Intersection {
+    var inter = intersect;
+    let instance = instances[instance_idx];
+    let local_o = opTransform(ray_o, instance.inverse_transform);
+    let local_id = 1.0 / (instance.inverse_transform * vec4f(ray_d, 0.0)).xyz;
+
+    var stack: array<u32, BLAS_STACK>;
+    var stackPtr: i32 = 0;
+    stack[stackPtr] = instance.shape.y;
+    stackPtr = stackPtr + 1;
+
+    loop {
+        if (stackPtr == 0) {
+            break;
+        }
+        stackPtr = stackPtr - 1;
+        let node_idx = stack[stackPtr];
+        let node = blas_nodes[node_idx];
+
+        let part_dists = intersect_aabb_dist(local_o, local_id, node.aabb, inter.t);
+        if (part_dists.y <= 0.0 || part_dists.x > inter.t) {
+            continue;
+        }
+
+        if (node.child1.x != 0) {
+            let start = max(part_dists.x, dists.x);
+            let end = min(part_dists.x + part_dists.y, dists.x + dists.y);
+            if (end > start) {
+                inter = raymarch(vec2<u32>(BLAS_LEAF + node_idx, instance_idx), ray_o, ray_d, vec2f(start, end - start), inter);
+            }
+        } else {
+            let child1 = instance.shape.y + node.child1.y;
+            let child2 = instance.shape.y + node.child2.y;
+            let dists1 = intersect_aabb_dist(local_o, local_id, blas_nodes[child1].aabb, inter.t);
+            let dists2 = intersect_aabb_dist(local_o, local_id, blas_nodes[child2].aabb, inter.t);
+            var near = child1;
+            var far = child2;
+            if (dists2.x < dists1.x) {
+                near = child2;
+                far = child1;
+            }
+            if (stackPtr < i32(BLAS_STACK)) {
+                stack[stackPtr] = far;
+                stackPtr = stackPtr + 1;
+            }
+            if (stackPtr < i32(BLAS_STACK)) {
+                stack[stackPtr] = near;
+                stackPtr = stackPtr + 1;
+            }
+        }
+    }
+
     return inter;
 }
 
+fn compound_shadow(instance_idx: u32, ray_o: vec3<f32>, ray_d: vec3<f32>, dists: vec2<f32>, penumbra: f32) -> f32 {
+    var shadow_res = 1.0;
+    let instance = instances[instance_idx];
+    let local_o = opTransform(ray_o, instance.inverse_transform);
+    let local_id = 1.0 / (instance.inverse_transform * vec4f(ray_d, 0.0)).xyz;
+
+    var stack: array<u32, BLAS_STACK>;
+    var stackPtr: i32 = 0;
+    stack[stackPtr] = instance.shape.y;
+    stackPtr = stackPtr + 1;
+
+    loop {
+        if (stackPtr == 0 || shadow_res < 0.0001) {
+            break;
+        }
+        stackPtr = stackPtr - 1;
+        let node_idx = stack[stackPtr];
+        let node = blas_nodes[node_idx];
+
+        let part_dists = intersect_aabb_dist(local_o, local_id, node.aabb, dists.x + dists.y);
+        if (part_dists.y <= 0.0) {
+            continue;
+        }
+
+        if (node.child1.x != 0) {
+            let start = max(part_dists.x, dists.x);
+            let end = min(part_dists.x + part_dists.y, dists.x + dists.y);
+            if (end > start) {
+                shadow_res = min(shadow_res, soft_shadow_raymarch(vec2<u32>(BLAS_LEAF + node_idx, instance_idx), ray_o, ray_d, vec2f(start, end - start), penumbra));
+            }
+        } else {
+            if (stackPtr < i32(BLAS_STACK)) {
+                stack[stackPtr] = instance.shape.y + node.child2.y;
+                stackPtr = stackPtr + 1;
+            }
+            if (stackPtr < i32(BLAS_STACK)) {
+                stack[stackPtr] = instance.shape.y + node.child1.y;
+                stackPtr = stackPtr + 1;
+            }
+        }
+    }
+
+    return shadow_res;
+}
+
+fn compound_cone(instance_idx: u32, ray_o: vec3<f32>, ray_d: vec3<f32>, dists: vec2<f32>, slope: f32, nearest: f32) -> f32 {
+    var best = nearest;
+    let instance = instances[instance_idx];
+    let local_o = opTransform(ray_o, instance.inverse_transform);
+    let local_id = 1.0 / (instance.inverse_transform * vec4f(ray_d, 0.0)).xyz;
+    let local_slope = slope * length((instance.inverse_transform * vec4f(ray_d, 0.0)).xyz); //the cone widens by the same t in both spaces
+
+    var stack: array<u32, BLAS_STACK>;
+    var stackPtr: i32 = 0;
+    stack[stackPtr] = instance.shape.y;
+    stackPtr = stackPtr + 1;
+
+    loop {
+        if (stackPtr == 0) {
+            break;
+        }
+        stackPtr = stackPtr - 1;
+        let node_idx = stack[stackPtr];
+        let node = blas_nodes[node_idx];
+
+        let part_dists = intersect_aabb_dist(local_o, local_id, cone_aabb(node.aabb, local_o, local_slope), best);
+        if (part_dists.y <= 0.0 || part_dists.x > best) {
+            continue;
+        }
+
+        if (node.child1.x != 0) {
+            let start = max(part_dists.x, dists.x);
+            let end = min(part_dists.x + part_dists.y, dists.x + dists.y);
+            if (end > start) {
+                best = cone_march(vec2<u32>(BLAS_LEAF + node_idx, instance_idx), ray_o, ray_d, vec2f(start, end - start), slope, best);
+            }
+        } else {
+            if (stackPtr < i32(BLAS_STACK)) {
+                stack[stackPtr] = instance.shape.y + node.child2.y;
+                stackPtr = stackPtr + 1;
+            }
+            if (stackPtr < i32(BLAS_STACK)) {
+                stack[stackPtr] = instance.shape.y + node.child1.y;
+                stackPtr = stackPtr + 1;
+            }
+        }
+    }
+
+    return best;
+}
+
 
 fn intersect_aabb_dist(
     ray_origin: vec3<f32>,
@@ -337,19 +1327,33 @@ fn raymarch(
     //let step_size: f32 = dists.y / 48.0; // Define a suitable step size
 
     loop{
-        if (count >= 64 || t >= max){
+        if (count >= raymarch_settings.max_steps || t >= max){
             break;
         }
         var dist = map(ray_o + ray_d * t, shape_idx);
-        if (dist < (0.001 * t * 2)) {
+        if (dist < (raymarch_settings.hit_epsilon * t)) {
             if (t < intersect.t) {
             var pos = ray_o + ray_d * t;
-            var normal = calc_normal(pos, dist, vec3f(0.0, 0.0, 0.0), 1.0, shape_idx);
+            var normal = calc_normal(pos, dist, vec3f(0.0, 0.0, 0.0), 1.0, shape_idx, t);
             
 
             inter.normal = normal;
             
-            inter.colour = get_colour(shape_idx);
+            let shape = instance_shape(shape_idx);
+            inter.colour = get_colour(shape);
+            inter.emissive = get_emissive(shape);
+
+            var surface = get_surface(shape);
+            if is_instanced(shape_idx) {
+                surface.inv_transform = surface.inv_transform * instances[shape_idx.y].inverse_transform;
+            }
+            if surface.pattern != 0u {
+                inter.colour = pattern_colour(pos, inter.colour, surface);
+            }
+            if surface.layer != 0u {
+                inter.colour *= triplanar_albedo(pos, normal, surface);
+                inter.normal = triplanar_normal(pos, normal, surface);
+            }
             
             inter.hit_pos = ray_o + ray_d * t;
 
@@ -368,9 +1372,21 @@ fn raymarch(
     return inter;
 }
 
-fn calc_normal(p: vec3<f32>, c: f32, sp: vec3<f32>, ss: f32, idx: vec2<u32>) -> vec3<f32>{
+fn calc_normal(p: vec3<f32>, c: f32, sp: vec3<f32>, ss: f32, idx: vec2<u32>, t: f32) -> vec3<f32>{
+    let eps = raymarch_settings.normal_epsilon + raymarch_settings.normal_epsilon_scale * t;
+
+    if raymarch_settings.normal_mode == 2u {
+        let analytic = analytic_normal(p, idx);
+        if any(analytic != vec3f(0.0)) {
+            return analytic;
+        }
+    }
+    if raymarch_settings.normal_mode != 0u {
+        return tetrahedral_normal(p, idx, eps);
+    }
+
     // Define the epsilon vector
-    let eps_zero: vec2<f32> = vec2<f32>(0.001, 0.0);
+    let eps_zero: vec2<f32> = vec2<f32>(eps, 0.0);
 
     // Manually create the swizzled positions
     let pos_xyy: vec3<f32> = p + vec3<f32>(eps_zero.x, eps_zero.y, eps_zero.y);
@@ -387,6 +1403,64 @@ fn calc_normal(p: vec3<f32>, c: f32, sp: vec3<f32>, ss: f32, idx: vec2<u32>) ->
     return normalize(gradient);
 } 
 
+// central differences from four taps on a tetrahedron, one more map call than forward differences but no bias
+fn tetrahedral_normal(p: vec3<f32>, idx: vec2<u32>, eps: f32) -> vec3<f32> {
+    let k = vec2f(1.0, -1.0);
+    return normalize(
+        k.xyy * map(p + k.xyy * eps, idx) +
+        k.yyx * map(p + k.yyx * eps, idx) +
+        k.yxy * map(p + k.yxy * eps, idx) +
+        k.xxx * map(p + k.xxx * eps, idx)
+    );
+}
+
+// closed form gradients in the shape's local space, zero for shapes that don't have one or are displaced, deformed or hollow
+fn analytic_normal(p: vec3<f32>, idx: vec2<u32>) -> vec3<f32> {
+    var local = vec3f(0.0);
+    var inv_transform: mat4x4<f32>;
+    switch idx.x {
+        default {
+            return vec3f(0.0);
+        }
+        case 1u {
+            let sphere = spheres[idx.y];
+            if sphere.displacement.amplitude != 0.0 || is_deformed(sphere.deform) || sphere.onion != 0.0 {
+                return vec3f(0.0);
+            }
+            inv_transform = sphere.inverse_transform;
+            local = GradSphere(repeat(opTransform(p, inv_transform), sphere.repeat));
+        }
+        case 2u {
+            let cube = cubes[idx.y];
+            if cube.displacement.amplitude != 0.0 || is_deformed(cube.deform) || cube.onion != 0.0 {
+                return vec3f(0.0);
+            }
+            inv_transform = cube.inverse_transform;
+            local = GradCube(repeat(opTransform(p, inv_transform), cube.repeat), cube.size);
+        }
+        case 4u {
+            let torus = toruses[idx.y];
+            if torus.displacement.amplitude != 0.0 || is_deformed(torus.deform) || torus.onion != 0.0 {
+                return vec3f(0.0);
+            }
+            inv_transform = torus.inverse_transform;
+            local = GradTorus(repeat(opTransform(p, inv_transform), torus.repeat), torus.radii.x);
+        }
+        case 5u {
+            let cylinder = cylinders[idx.y];
+            if cylinder.displacement.amplitude != 0.0 || is_deformed(cylinder.deform) || cylinder.onion != 0.0 {
+                return vec3f(0.0);
+            }
+            inv_transform = cylinder.inverse_transform;
+            local = GradCylinder(repeat(opTransform(p, inv_transform), cylinder.repeat), cylinder.height, cylinder.radius);
+        }
+    }
+
+    // normals go back to world space with the inverse transpose, which is the transpose of the inverse we already have
+    let inv = mat3x3<f32>(inv_transform[0].xyz, inv_transform[1].xyz, inv_transform[2].xyz);
+    return normalize(transpose(inv) * local);
+}
+
 fn get_colour(idx: vec2<u32>) -> vec3<f32> {
     var colour: vec3<f32>;
     switch idx.x {
@@ -417,6 +1491,9 @@ fn get_colour(idx: vec2<u32>) -> vec3<f32> {
             let cone = cones[idx.y];
             colour = cone.colour;
             }
+            case 8u {
+            colour = terrains[idx.y].colour;
+            }
     }
 
 
@@ -424,7 +1501,262 @@ fn get_colour(idx: vec2<u32>) -> vec3<f32> {
 }
 
 
+fn get_surface(idx: vec2<u32>) -> ShapeSurface {
+    var surface = ShapeSurface(0u, 1.0, 0u, 1.0, vec3f(0.0), mat4x4<f32>());
+    switch idx.x {
+            default {}
+            case 1u {
+            let sphere = spheres[idx.y];
+            surface = ShapeSurface(sphere.texture, sphere.texture_scale, sphere.pattern, sphere.pattern_scale, sphere.pattern_colour, sphere.inverse_transform);
+            }
+            case 2u {
+            let cube = cubes[idx.y];
+            surface = ShapeSurface(cube.texture, cube.texture_scale, cube.pattern, cube.pattern_scale, cube.pattern_colour, cube.inverse_transform);
+            }
+            case 3u {
+            let ellipse = ellipses[idx.y];
+            surface = ShapeSurface(ellipse.texture, ellipse.texture_scale, ellipse.pattern, ellipse.pattern_scale, ellipse.pattern_colour, ellipse.inverse_transform);
+            }
+            case 4u {
+            let torus = toruses[idx.y];
+            surface = ShapeSurface(torus.texture, torus.texture_scale, torus.pattern, torus.pattern_scale, torus.pattern_colour, torus.inverse_transform);
+            }
+            case 5u {
+            let cylinder = cylinders[idx.y];
+            surface = ShapeSurface(cylinder.texture, cylinder.texture_scale, cylinder.pattern, cylinder.pattern_scale, cylinder.pattern_colour, cylinder.inverse_transform);
+            }
+            case 6u {
+            let cone = cones[idx.y];
+            surface = ShapeSurface(cone.texture, cone.texture_scale, cone.pattern, cone.pattern_scale, cone.pattern_colour, cone.inverse_transform);
+            }
+    }
+    if surface.texture_scale == 0.0 {
+        surface.texture_scale = 1.0;
+    }
+    if surface.pattern_scale == 0.0 {
+        surface.pattern_scale = 1.0;
+    }
+    return surface;
+}
+
+// world normal into the shape's local space. normals go through the inverse transpose of the point transform,
+// here the inverse of transpose(inv), and only the direction matters so that's inv's cofactor matrix. det flips it
+// back for mirrored transforms
+fn local_normal(n: vec3<f32>, surface: ShapeSurface) -> vec3<f32> {
+    let a = surface.inv_transform[0].xyz;
+    let b = surface.inv_transform[1].xyz;
+    let c = surface.inv_transform[2].xyz;
+    let cofactor = mat3x3<f32>(cross(b, c), cross(c, a), cross(a, b));
+    return normalize(cofactor * n * sign(dot(a, cross(b, c))));
+}
+
+// blend weights for the three projections from the local normal, sharpened so the seams stay narrow
+fn triplanar_weights(n: vec3<f32>, surface: ShapeSurface) -> vec3<f32> {
+    var w = pow(abs(local_normal(n, surface)), vec3f(4.0));
+    return w / (w.x + w.y + w.z);
+}
+
+// explicit lod, there are no derivatives in compute and the marching loop isn't uniform control flow anyway
+fn triplanar_albedo(p: vec3<f32>, n: vec3<f32>, surface: ShapeSurface) -> vec3<f32> {
+    let local = opTransform(p, surface.inv_transform) * surface.texture_scale;
+    let w = triplanar_weights(n, surface);
+    let layer = surface.layer - 1u;
+    let x = textureSampleLevel(albedo_textures, texture_sampler, local.zy, layer, 0.0).rgb;
+    let y = textureSampleLevel(albedo_textures, texture_sampler, local.xz, layer, 0.0).rgb;
+    let z = textureSampleLevel(albedo_textures, texture_sampler, local.xy, layer, 0.0).rgb;
+    return x * w.x + y * w.y + z * w.z;
+}
+
+// whiteout blend: each tangent space normal is added onto the local normal swizzled into its plane,
+// then the result goes back to world space with the transpose of the inverse like analytic_normal
+fn triplanar_normal(p: vec3<f32>, n: vec3<f32>, surface: ShapeSurface) -> vec3<f32> {
+    let local = opTransform(p, surface.inv_transform) * surface.texture_scale;
+    let inv = mat3x3<f32>(surface.inv_transform[0].xyz, surface.inv_transform[1].xyz, surface.inv_transform[2].xyz);
+    let ln = local_normal(n, surface);
+    let w = triplanar_weights(n, surface);
+    let layer = surface.layer - 1u;
+    let tx = textureSampleLevel(normal_textures, texture_sampler, local.zy, layer, 0.0).xyz * 2.0 - 1.0;
+    let ty = textureSampleLevel(normal_textures, texture_sampler, local.xz, layer, 0.0).xyz * 2.0 - 1.0;
+    let tz = textureSampleLevel(normal_textures, texture_sampler, local.xy, layer, 0.0).xyz * 2.0 - 1.0;
+    let nx = vec3f(tx.xy + ln.zy, abs(tx.z) * ln.x);
+    let ny = vec3f(ty.xy + ln.xz, abs(ty.z) * ln.y);
+    let nz = vec3f(tz.xy + ln.xy, abs(tz.z) * ln.z);
+    let blended = normalize(nx.zyx * w.x + ny.xzy * w.y + nz.xyz * w.z);
+    return normalize(transpose(inv) * blended);
+}
+
+
+// flat patterns in the shape's local space, blending its colour into pattern_colour
+fn pattern_colour(p: vec3<f32>, colour: vec3<f32>, surface: ShapeSurface) -> vec3<f32> {
+    let q = opTransform(p, surface.inv_transform) * surface.pattern_scale;
+    var blend = 0.0;
+    switch surface.pattern {
+        default {}
+        case 1u {
+            let c = floor(q);
+            blend = abs(c.x + c.y + c.z) % 2.0;
+        }
+        case 2u {
+            blend = step(0.5, fract(q.x));
+        }
+        case 3u {
+            blend = value_noise(q);
+        }
+        case 4u {
+            blend = perlin_noise(q) * 0.5 + 0.5;
+        }
+        case 5u {
+            blend = cellular_noise(q);
+        }
+    }
+    return mix(colour, surface.pattern_colour, clamp(blend, 0.0, 1.0));
+}
+
+fn lattice_hash(c: vec3<f32>) -> u32 {
+    let i = vec3<u32>(vec3<i32>(c));
+    return hash(i.x ^ hash(i.y ^ hash(i.z)));
+}
+
+fn unit_float(h: u32) -> f32 {
+    return f32(h) / 4294967295.0;
+}
+
+// trilinear blend of random values at the lattice corners, 0..1
+fn value_noise(p: vec3<f32>) -> f32 {
+    let c = floor(p);
+    let f = fract(p);
+    let u = f * f * (3.0 - 2.0 * f);
+    return mix(
+        mix(
+            mix(unit_float(lattice_hash(c)), unit_float(lattice_hash(c + vec3f(1.0, 0.0, 0.0))), u.x),
+            mix(unit_float(lattice_hash(c + vec3f(0.0, 1.0, 0.0))), unit_float(lattice_hash(c + vec3f(1.0, 1.0, 0.0))), u.x),
+            u.y,
+        ),
+        mix(
+            mix(unit_float(lattice_hash(c + vec3f(0.0, 0.0, 1.0))), unit_float(lattice_hash(c + vec3f(1.0, 0.0, 1.0))), u.x),
+            mix(unit_float(lattice_hash(c + vec3f(0.0, 1.0, 1.0))), unit_float(lattice_hash(c + vec3f(1.0, 1.0, 1.0))), u.x),
+            u.y,
+        ),
+        u.z,
+    );
+}
+
+fn lattice_gradient(c: vec3<f32>) -> vec3<f32> {
+    let h = lattice_hash(c);
+    let h2 = hash(h);
+    return normalize(vec3f(unit_float(h), unit_float(h2), unit_float(hash(h2))) * 2.0 - 1.0 + 1e-5);
+}
+
+fn gradient_dot(c: vec3<f32>, f: vec3<f32>, corner: vec3<f32>) -> f32 {
+    return dot(lattice_gradient(c + corner), f - corner);
+}
+
+// random gradients at the lattice corners with a quintic fade, roughly -1..1
+fn perlin_noise(p: vec3<f32>) -> f32 {
+    let c = floor(p);
+    let f = fract(p);
+    let u = f * f * f * (f * (f * 6.0 - 15.0) + 10.0);
+    return mix(
+        mix(
+            mix(gradient_dot(c, f, vec3f(0.0, 0.0, 0.0)), gradient_dot(c, f, vec3f(1.0, 0.0, 0.0)), u.x),
+            mix(gradient_dot(c, f, vec3f(0.0, 1.0, 0.0)), gradient_dot(c, f, vec3f(1.0, 1.0, 0.0)), u.x),
+            u.y,
+        ),
+        mix(
+            mix(gradient_dot(c, f, vec3f(0.0, 0.0, 1.0)), gradient_dot(c, f, vec3f(1.0, 0.0, 1.0)), u.x),
+            mix(gradient_dot(c, f, vec3f(0.0, 1.0, 1.0)), gradient_dot(c, f, vec3f(1.0, 1.0, 1.0)), u.x),
+            u.y,
+        ),
+        u.z,
+    ) * 1.15;
+}
+
+// distance to the nearest of one random point per cell, 0 at the points and about 1 at the cell borders
+fn cellular_noise(p: vec3<f32>) -> f32 {
+    let c = floor(p);
+    let f = fract(p);
+    var nearest = 8.0;
+    for (var z = -1; z <= 1; z++) {
+        for (var y = -1; y <= 1; y++) {
+            for (var x = -1; x <= 1; x++) {
+                let offset = vec3f(f32(x), f32(y), f32(z));
+                let h = lattice_hash(c + offset);
+                let h2 = hash(h);
+                let point = offset + vec3f(unit_float(h), unit_float(h2), unit_float(hash(h2)));
+                nearest = min(nearest, length(point - f));
+            }
+        }
+    }
+    return nearest;
+}
+
+
+fn get_emissive(idx: vec2<u32>) -> f32 {
+    var emissive: f32;
+    switch idx.x {
+            default {
+                emissive = 0.0;
+            }
+            case 1u {
+            emissive = spheres[idx.y].emissive;
+            }
+            case 2u {
+            emissive = cubes[idx.y].emissive;
+            }
+            case 3u {
+            emissive = ellipses[idx.y].emissive;
+            }
+            case 4u {
+            emissive = toruses[idx.y].emissive;
+            }
+            case 5u {
+            emissive = cylinders[idx.y].emissive;
+            }
+            case 6u {
+            emissive = cones[idx.y].emissive;
+            }
+    }
+    return emissive;
+}
+
+
+// instances point at a prototype stored once in the shape arrays, and put their own transform in front of its
 fn map(p: vec3<f32>, idx: vec2<u32>) -> f32{
+    if is_instanced(idx) {
+        let instance = instances[idx.y];
+        return map_shape(opTransform(p, instance.inverse_transform), instance_shape(idx)) * instance.transform_determinant;
+    }
+    return map_shape(p, idx);
+}
+
+// an instance of a single shape prototype, or one part of a compound instance
+fn is_instanced(idx: vec2<u32>) -> bool {
+    return idx.x == 9u || idx.x >= BLAS_LEAF;
+}
+
+// an instance leaf in the top level tree whose prototype has a bottom level tree of parts
+fn is_compound(idx: vec2<u32>) -> bool {
+    return idx.x == 9u && instances[idx.y].shape.x == 10u;
+}
+
+// the prototype's own slot in the shape arrays, with the part's transform relative to the prototype. the parts are
+// packed once on the cpu with slots of their own, they sit after the scene's shapes of the same type
+fn instance_shape(idx: vec2<u32>) -> vec2<u32> {
+    var shape = idx;
+    if idx.x >= BLAS_LEAF {
+        shape = blas_nodes[idx.x - BLAS_LEAF].child1;
+    } else if idx.x == 9u {
+        shape = instances[idx.y].shape;
+    } else {
+        return idx;
+    }
+    if shape.x < arrayLength(&part_offsets) {
+        shape.y += part_offsets[shape.x];
+    }
+    return shape;
+}
+
+fn map_shape(p: vec3<f32>, idx: vec2<u32>) -> f32{
     var dist: f32 = 10000.0;
     switch idx.x {
         default {
@@ -432,35 +1764,141 @@ fn map(p: vec3<f32>, idx: vec2<u32>) -> f32{
         }
         case 1u {
             let sphere = spheres[idx.y];
-            
-            dist = SdfSphere(opTransform(p, sphere.inverse_transform), sphere.radius) * sphere.transform_determinant;
+            let deformed = deform(repeat(opTransform(p, sphere.inverse_transform), sphere.repeat), sphere.deform);
+            let q = deformed.q;
+            dist = displace(shell(SdfSphere(q, sphere.radius), sphere.rounding, sphere.onion, sphere.onion_layers), q, sphere.displacement) / deformed.lipschitz * sphere.transform_determinant;
         }
         case 2u {
             let cube = cubes[idx.y];
-            
-            dist = SdfCube(opTransform(p, cube.inverse_transform), cube.size) * cube.transform_determinant;
+            let deformed = deform(repeat(opTransform(p, cube.inverse_transform), cube.repeat), cube.deform);
+            let q = deformed.q;
+            dist = displace(shell(SdfCube(q, cube.size), cube.rounding, cube.onion, cube.onion_layers), q, cube.displacement) / deformed.lipschitz * cube.transform_determinant;
 
         }
         case 3u {
             let ellipse = ellipses[idx.y];
-            dist = SdfEllipsoid(opTransform(p, ellipse.inverse_transform), ellipse.radii) * ellipse.transform_determinant;
+            let deformed = deform(repeat(opTransform(p, ellipse.inverse_transform), ellipse.repeat), ellipse.deform);
+            let q = deformed.q;
+            dist = displace(shell(SdfEllipsoid(q, ellipse.radii), ellipse.rounding, ellipse.onion, ellipse.onion_layers), q, ellipse.displacement) / deformed.lipschitz * ellipse.transform_determinant;
         }
         case 4u {
             let torus = toruses[idx.y];
-            dist = SdfTorus(opTransform(p, torus.inverse_transform), torus.radii.x, torus.radii.y) * torus.transform_determinant;
+            let deformed = deform(repeat(opTransform(p, torus.inverse_transform), torus.repeat), torus.deform);
+            let q = deformed.q;
+            dist = displace(shell(SdfTorus(q, torus.radii.x, torus.radii.y), torus.rounding, torus.onion, torus.onion_layers), q, torus.displacement) / deformed.lipschitz * torus.transform_determinant;
         }
         case 5u {
             let cylinder = cylinders[idx.y];
-            dist = SdfCylinder(opTransform(p, cylinder.inverse_transform), cylinder.height, cylinder.radius) * cylinder.transform_determinant;
+            let deformed = deform(repeat(opTransform(p, cylinder.inverse_transform), cylinder.repeat), cylinder.deform);
+            let q = deformed.q;
+            dist = displace(shell(SdfCylinder(q, cylinder.height, cylinder.radius), cylinder.rounding, cylinder.onion, cylinder.onion_layers), q, cylinder.displacement) / deformed.lipschitz * cylinder.transform_determinant;
         }
         case 6u {
             let cone = cones[idx.y];
-            dist = SdfCone(opTransform(p, cone.inverse_transform), cone.height, cone.sincos) * cone.transform_determinant;
+            let deformed = deform(repeat(opTransform(p, cone.inverse_transform), cone.repeat), cone.deform);
+            let q = deformed.q;
+            dist = displace(shell(SdfCone(q, cone.height, cone.sincos), cone.rounding, cone.onion, cone.onion_layers), q, cone.displacement) / deformed.lipschitz * cone.transform_determinant;
         }
+        case 8u {
+            let terrain = terrains[idx.y];
+            let q = opTransform(p, terrain.inverse_transform);
+            dist = SdfTerrain(q, terrain) * terrain.transform_determinant;
+        }
+
+    }
+    return dist;
+
+}
 
+// rounding grows the surface out by a radius, softening its edges. onion then hollows it into a shell that thick,
+// each extra layer splits the shells again at half the thickness
+fn shell(d: f32, rounding: f32, onion: f32, layers: u32) -> f32 {
+    var dist = d - rounding;
+    if onion != 0.0 {
+        var thickness = abs(onion);
+        for (var i = 0u; i < max(layers, 1u); i++) {
+            dist = abs(dist) - thickness;
+            thickness *= 0.5;
+        }
     }
     return dist;
+}
 
+// folds the local point into the nearest copy of a grid centred on the shape, a spacing of 0 leaves that axis alone.
+// distances stay exact as long as the shape fits inside its cell
+fn repeat(p: vec3<f32>, repeat: ShapeRepeat) -> vec3<f32> {
+    if repeat.mode == 0u {
+        return p;
+    }
+    let unrepeated = repeat.spacing == vec3f(0.0);
+    let spacing = select(repeat.spacing, vec3f(1.0), unrepeated);
+    var id = round(p / spacing);
+    if repeat.mode == 1u {
+        let half = (vec3<f32>(max(repeat.count, vec3(1u))) - 1.0) * 0.5;
+        id = clamp(round(p / spacing + half), vec3f(0.0), half * 2.0) - half;
+    }
+    return select(p - spacing * id, p, unrepeated);
+}
+
+fn is_deformed(deform: SdDeform) -> bool {
+    return deform.twist != 0.0 || deform.bend != 0.0 || deform.taper != 0.0 || any(deform.shear != vec2f(0.0));
+}
+
+// warps the local point before the primitive sees it: twist around y, bend in xy, taper xz along y, shear xz by y.
+// the distance has to be divided by how much the warp can stretch space, which is estimated from the current point
+fn deform(p: vec3<f32>, deform: SdDeform) -> Deformed {
+    var q = p;
+    var lipschitz = 1.0;
+
+    if deform.twist != 0.0 {
+        let c = cos(deform.twist * q.y);
+        let s = sin(deform.twist * q.y);
+        q = vec3f(c * q.x - s * q.z, q.y, s * q.x + c * q.z);
+        let r = deform.twist * length(q.xz);
+        lipschitz *= sqrt(1.0 + r * r);
+    }
+    if deform.bend != 0.0 {
+        let c = cos(deform.bend * q.x);
+        let s = sin(deform.bend * q.x);
+        q = vec3f(c * q.x - s * q.y, s * q.x + c * q.y, q.z);
+        let r = deform.bend * length(q.xy);
+        lipschitz *= sqrt(1.0 + r * r);
+    }
+    if deform.taper != 0.0 {
+        let scale = max(1.0 + deform.taper * q.y, 0.05);
+        lipschitz *= (1.0 + abs(deform.taper) * length(q.xz) / scale) / scale;
+        q = vec3f(q.x / scale, q.y, q.z / scale);
+    }
+    if any(deform.shear != vec2f(0.0)) {
+        q = vec3f(q.x - deform.shear.x * q.y, q.y, q.z - deform.shear.y * q.y);
+        lipschitz *= 1.0 + length(deform.shear);
+    }
+
+    return Deformed(q, lipschitz);
+}
+
+// pushes the surface out along perlin noise or a product of sines in local space, then divides by one plus the
+// steepest slope the offset can have, so the result stays a lower bound and steps can't overshoot the surface
+fn displace(d: f32, q: vec3<f32>, displacement: SdDisplacement) -> f32 {
+    if displacement.amplitude == 0.0 {
+        return d;
+    }
+    var frequency = displacement.frequency;
+    if frequency == 0.0 {
+        frequency = 1.0;
+    }
+
+    var offset: f32;
+    var slope: f32;
+    if displacement.kind == 1u {
+        let s = sin(q * frequency);
+        offset = s.x * s.y * s.z;
+        slope = frequency * 1.7320508;
+    } else {
+        offset = perlin_noise(q * frequency);
+        slope = frequency * 2.5;
+    }
+    return (d + displacement.amplitude * offset) / (1.0 + abs(displacement.amplitude) * slope);
 }
 
 fn opTransform(p: vec3<f32>, inv_transform: mat4x4<f32>) -> vec3<f32> {
@@ -468,6 +1906,26 @@ fn opTransform(p: vec3<f32>, inv_transform: mat4x4<f32>) -> vec3<f32> {
     return q.xyz; // Return the transformed position
 }
 
+// height above the bilinear surface through the samples, scaled by the steepest slope between any two of them so
+// it stays a lower bound on the distance. outside the footprint the horizontal distance to it takes over
+fn SdfTerrain(p: vec3f, terrain: TerrainShape) -> f32 {
+    let res = terrain.resolution;
+    if (any(res < vec2(2u))) {
+        return 10000.0; //not baked yet
+    }
+    let uv = clamp(p.xz / terrain.size * 0.5 + 0.5, vec2f(0.0), vec2f(1.0)) * vec2f(res - 1u);
+    let cell = min(vec2<u32>(uv), res - 2u);
+    let f = uv - vec2f(cell);
+    let i = terrain.height_offset + cell.y * res.x + cell.x;
+    let h = mix(
+        mix(terrain_heights[i], terrain_heights[i + 1u], f.x),
+        mix(terrain_heights[i + res.x], terrain_heights[i + res.x + 1u], f.x),
+        f.y,
+    ) * terrain.height;
+    let outside = max(abs(p.xz) - terrain.size, vec2f(0.0));
+    return max((p.y - h) * terrain.step, length(outside));
+}
+
 fn SdfSphere(p: vec3f, r: f32) -> f32 {
   return length(p) - r;
 }
@@ -496,15 +1954,58 @@ fn SdfCone(p: vec3f, h: f32, sincos: vec2f) -> f32 {
   return max(dot(sincos.yx, vec2f(length(p.xz), p.y)), -h - p.y);
 }
 
+fn GradSphere(p: vec3f) -> vec3f {
+  return normalize(p);
+}
+
+fn GradCube(p: vec3f, b: vec3f) -> vec3f {
+  let w = abs(p) - b;
+  let s = sign(p);
+  let g = max(w.x, max(w.y, w.z));
+  if g > 0. {
+    return s * normalize(max(w, vec3f(0.)));
+  }
+  if w.x > w.y && w.x > w.z {
+    return s * vec3f(1., 0., 0.);
+  }
+  if w.y > w.z {
+    return s * vec3f(0., 1., 0.);
+  }
+  return s * vec3f(0., 0., 1.);
+}
+
+fn GradTorus(p: vec3f, R: f32) -> vec3f {
+  let l = max(length(p.xz), 1e-6);
+  let q = vec2f(l - R, p.y);
+  return normalize(vec3f(p.x / l * q.x, q.y, p.z / l * q.x));
+}
+
+fn GradCylinder(p: vec3f, h: f32, r: f32) -> vec3f {
+  let l = max(length(p.xz), 1e-6);
+  let radial = vec3f(p.x / l, 0., p.z / l);
+  let cap = vec3f(0., sign(p.y), 0.);
+  let d = vec2f(l - r, abs(p.y) - h);
+  if d.x > 0. && d.y > 0. {
+    return normalize(radial * d.x + cap * d.y);
+  }
+  if d.x > d.y {
+    return radial;
+  }
+  return cap;
+}
+
 fn shadow_intersect(
     ray_o: vec3<f32>, 
     ray_d: vec3<f32>,
     ray_id: vec3<f32>,
+    max_t: f32, //stop short of the light, so an emissive shape doesn't shadow itself
+    penumbra: f32, //tangent of the cone towards the light, its size over its distance for lights that have one
 ) -> f32 {
-    let max_distance = raymarch_settings.max_distance; // e.g., 650.0
+    let max_distance = max_t;
     let default_idx = vec2<u32>(0, 0);
-    var stack: array<u32, 20>;
+    var stack: array<u32, MAX_STACK>;
     var stackPtr: i32 = 0;
+    let stack_size = i32(MAX_STACK);
     var shadow_res: f32 = 1.0; // Initialize shadow factor
 
     // Push the root index onto the stack
@@ -531,10 +2032,20 @@ fn shadow_intersect(
         let child2 = currentNode.child2;
 
         // Check if the node is a leaf node
+        if (child1.x == 7u) {
+            continue; //fog volume, only fog_volume_march looks at these
+        }
         if (child1.x != 0) {
             // Leaf node: Perform soft shadow raymarching
             let shape_idx = child1; // Assuming child1 encodes shape index
-            let s = soft_shadow_raymarch(shape_idx, ray_o, ray_d, current_dists, raymarch_settings.shadow_power);
+            var s: f32;
+            if (shape_idx.x == 8u) {
+                s = terrain_shadow(shape_idx.y, ray_o, ray_d, current_dists, penumbra);
+            } else if (is_compound(shape_idx)) {
+                s = compound_shadow(shape_idx.y, ray_o, ray_d, current_dists, penumbra);
+            } else {
+                s = soft_shadow_raymarch(shape_idx, ray_o, ray_d, current_dists, penumbra);
+            }
             shadow_res = min(shadow_res, s); // Accumulate shadow factor
 
             // Early termination if shadow is fully blocked
@@ -548,20 +2059,20 @@ fn shadow_intersect(
 
             // Order traversal based on entry distances for potential performance gains
             if (dists1.x < dists2.x) {
-                if (dists2.y > 0.0) {
+                if (dists2.y > 0.0 && stackPtr < stack_size) {
                     stack[stackPtr] = currentNode.child2.y;
                     stackPtr = stackPtr + 1;
                 }
-                if (dists1.y > 0.0) {
+                if (dists1.y > 0.0 && stackPtr < stack_size) {
                     stack[stackPtr] = currentNode.child1.y;
                     stackPtr = stackPtr + 1;
                 }
             } else {
-                if (dists1.y > 0.0) {
+                if (dists1.y > 0.0 && stackPtr < stack_size) {
                     stack[stackPtr] = currentNode.child1.y;
                     stackPtr = stackPtr + 1;
                 }
-                if (dists2.y > 0.0) {
+                if (dists2.y > 0.0 && stackPtr < stack_size) {
                     stack[stackPtr] = currentNode.child2.y;
                     stackPtr = stackPtr + 1;
                 }
@@ -569,6 +2080,10 @@ fn shadow_intersect(
         }
     }
 
+    for (var i = 0u; i < arrayLength(&unbounded) && shadow_res >= 0.0001; i++) {
+        shadow_res = min(shadow_res, soft_shadow_raymarch(unbounded[i], ray_o, ray_d, vec2f(0.0, max_distance), penumbra));
+    }
+
     // Clamp and smooth the final shadow factor
     shadow_res = clamp(shadow_res, 0.0, 1.0);
     shadow_res = shadow_res * shadow_res * (3.0 - 2.0 * shadow_res); // Smoothstep-like function
@@ -622,8 +2137,8 @@ fn soft_shadow_raymarch(
     var res: f32 = 1.0;
     var t: f32 = dists.x;
     let maxt: f32 = dists.x + dists.y;
-    let max_steps: u32 = 8u;      // Increased steps to allow deeper penetration
-    let min_step: f32 = dists.y / 8.0;      // Minimum step size
+    let max_steps: u32 = max(raymarch_settings.shadow_steps, 1u);
+    let min_step: f32 = dists.y / f32(max_steps);      // Minimum step size
     let max_step: f32 = dists.y / 2.0;       // Maximum step size
 
     for (var i: u32 = 0u; i < max_steps && t < maxt; i = i + 1u) {
@@ -0,0 +1,102 @@
+#import bevy_pbr::mesh_view_bindings::view
+
+@group(2) @binding(0) var source: texture_2d<f32>;
+@group(2) @binding(1) var source_sampler: sampler;
+@group(2) @binding(2) var<uniform> filter_mode: u32;
+@group(2) @binding(3) var<uniform> max_distance: f32;
+@group(2) @binding(4) var depth: texture_2d<f32>; //the hit distance, r32float so it isn't filterable
+
+struct Vertex {
+    @location(0) position: vec3<f32>,
+    @location(2) uv: vec2<f32>,
+};
+
+struct VertexOutput {
+    @builtin(position) position: vec4<f32>,
+    @location(0) uv: vec2<f32>,
+};
+
+struct FragmentOutput {
+    @location(0) colour: vec4<f32>,
+    @builtin(frag_depth) depth: f32,
+};
+
+// the quad is -0.5..0.5, so doubling it covers clip space regardless of the camera
+@vertex
+fn vertex(vertex: Vertex) -> VertexOutput {
+    var out: VertexOutput;
+    out.position = vec4f(vertex.position.xy * 2.0, 0.0, 1.0);
+    out.uv = vertex.uv;
+    return out;
+}
+
+@fragment
+fn fragment(in: VertexOutput) -> FragmentOutput {
+    var out: FragmentOutput;
+
+    switch filter_mode {
+        default {
+            out.colour = vec4f(textureSample(source, source_sampler, in.uv).rgb, 1.0);
+        }
+        case 1u {
+            out.colour = vec4f(edge_aware(in.uv), 1.0);
+        }
+    }
+
+    out.depth = hit_depth(in.uv);
+    return out;
+}
+
+// depth holds the distance along the camera ray, project that point with the real camera to get its depth
+fn hit_depth(uv: vec2<f32>) -> f32 {
+    let size = vec2<i32>(textureDimensions(depth));
+    let texel = clamp(vec2<i32>(uv * vec2f(size)), vec2i(0), size - 1);
+    let t = textureLoad(depth, texel, 0).r;
+    if t >= max_distance * 0.999 {
+        return 0.0; //sky, reverse z so this is the far plane
+    }
+
+    let ndc = vec2f(uv.x * 2.0 - 1.0, 1.0 - uv.y * 2.0);
+    let near = view.world_from_clip * vec4f(ndc, 1.0, 1.0);
+    let ray_d = normalize(near.xyz / near.w - view.world_position);
+    let hit = view.world_position + ray_d * t;
+
+    let clip = view.clip_from_world * vec4f(hit, 1.0);
+    return clamp(clip.z / clip.w, 0.0, 1.0);
+}
+
+fn luma(c: vec3<f32>) -> f32 {
+    return dot(c, vec3f(0.2126, 0.7152, 0.0722));
+}
+
+// bilinear, but texels that differ a lot from the nearest one get down weighted so edges stay sharp
+fn edge_aware(uv: vec2<f32>) -> vec3<f32> {
+    let size = vec2<i32>(textureDimensions(source));
+    let p = uv * vec2f(size) - 0.5;
+    let base = vec2<i32>(floor(p));
+    let f = p - floor(p);
+
+    let max_coord = size - 1;
+    let c00 = textureLoad(source, clamp(base, vec2i(0), max_coord), 0).rgb;
+    let c10 = textureLoad(source, clamp(base + vec2i(1, 0), vec2i(0), max_coord), 0).rgb;
+    let c01 = textureLoad(source, clamp(base + vec2i(0, 1), vec2i(0), max_coord), 0).rgb;
+    let c11 = textureLoad(source, clamp(base + vec2i(1, 1), vec2i(0), max_coord), 0).rgb;
+
+    var nearest = c00;
+    if f.x >= 0.5 && f.y < 0.5 { nearest = c10; }
+    if f.x < 0.5 && f.y >= 0.5 { nearest = c01; }
+    if f.x >= 0.5 && f.y >= 0.5 { nearest = c11; }
+    let l = luma(nearest);
+
+    let sharpness = 8.0;
+    let w00 = (1.0 - f.x) * (1.0 - f.y) * exp(-abs(luma(c00) - l) * sharpness);
+    let w10 = f.x * (1.0 - f.y) * exp(-abs(luma(c10) - l) * sharpness);
+    let w01 = (1.0 - f.x) * f.y * exp(-abs(luma(c01) - l) * sharpness);
+    let w11 = f.x * f.y * exp(-abs(luma(c11) - l) * sharpness);
+
+    let total = w00 + w10 + w01 + w11;
+    if total <= 0.0001 {
+        return nearest;
+    }
+    return (c00 * w00 + c10 * w10 + c01 * w01 + c11 * w11) / total;
+}
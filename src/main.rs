@@ -2,16 +2,43 @@ use bevy::{
     //color::palettes::tailwind, 
     dev_tools::fps_overlay::{FpsOverlayConfig, FpsOverlayPlugin}, 
     //ecs::component::{self, ComponentHooks, StorageType}, 
-    math::{uvec2, vec2, vec3}, 
+    core::FrameCount,
+    core_pipeline::{
+        auto_exposure::{AutoExposurePlugin, AutoExposureSettings},
+        bloom::BloomSettings,
+        core_3d::graph::{Core3d, Node3d},
+        tonemapping::Tonemapping,
+    },
+    math::{uvec2, uvec3, vec2, vec3}, 
+    pbr::{MaterialPipeline, MaterialPipelineKey, NotShadowCaster},
     prelude::*, 
     reflect::TypePath, 
-    render::render_resource::{AsBindGroup, ShaderRef, ShaderType}, 
-    sprite::{Material2d, Material2dPlugin, MaterialMesh2dBundle}
+    render::{
+        camera::RenderTarget,
+        mesh::MeshVertexBufferLayoutRef,
+        extract_resource::{ExtractResource, ExtractResourcePlugin},
+        graph::CameraDriverLabel,
+        render_asset::{RenderAssetUsages, RenderAssets},
+        render_graph::{self, RenderGraph, RenderGraphApp, RenderLabel},
+        render_resource::{
+            binding_types::{sampler, texture_2d, texture_storage_2d, uniform_buffer}, AsBindGroup, BindGroup, BindGroupEntries, 
+            BindGroupLayout, BindGroupLayoutEntries, CachedComputePipelineId, ComputePassDescriptor, ComputePipelineDescriptor, Extent3d, 
+            PipelineCache, RenderPipelineDescriptor, SamplerBindingType, ShaderDefVal, ShaderRef, ShaderStages, ShaderType, 
+            SpecializedComputePipeline, SpecializedComputePipelines, SpecializedMeshPipelineError, StorageTextureAccess, Texture, TextureDimension, TextureFormat, TextureSampleType, TextureUsages, 
+            TextureViewDescriptor, TextureViewDimension, UniformBuffer
+        },
+        renderer::{RenderContext, RenderDevice, RenderQueue},
+        texture::{GpuImage, ImageAddressMode, ImageFilterMode, ImageSampler, ImageSamplerDescriptor},
+        view::{ColorGrading, NoFrustumCulling, RenderLayers},
+        Extract, ExtractSchedule, Render, RenderApp, RenderSet,
+    }, 
+    sprite::{Material2d, Material2dKey, Material2dPlugin, MaterialMesh2dBundle, PreparedMaterial2d},
+    utils::HashMap,
 };
 
 
 
-use std::sync::{Arc, Mutex};
+use std::{borrow::Cow, sync::{Arc, Mutex}};
 use bevy_flycam::prelude::*;
 use bevy_inspector_egui::quick::WorldInspectorPlugin;
 use turborand::prelude::*;
@@ -30,6 +57,11 @@ fn main() {
                 ..default()
             }),
             Material2dPlugin::<RaymarchMaterial>::default(),
+            MaterialPlugin::<UpscaleMaterial>::default(),
+            AutoExposurePlugin,
+            RaymarchComputePlugin,
+            RaymarchConePlugin,
+            RaymarchTemporalPlugin,
             WorldInspectorPlugin::new(),
             FpsOverlayPlugin {
                 config: FpsOverlayConfig {
@@ -48,22 +80,38 @@ fn main() {
         .insert_resource(MovementSettings {
             ..Default::default()
         })
-        .register_type::<(SdCube, RayCamera, SdSphere, RaymarchSettings, SdDirectionalLight, SdEllipse, SdTorus, SdCylinder, SdCone)>()
+        .register_type::<(SdCube, RayCamera, SdSphere, RaymarchSettings, SdDirectionalLight, SdEllipse, SdTorus, SdCylinder, SdCone, SdFogVolume, SdEmissiveLight, SdSpotLight)>()
+        .register_type::<(RaymarchResolution, RaymarchBackend, RaymarchTemporal, RaymarchMode, RaymarchQuality, RaymarchConePrepass)>()
+        .register_type::<(TimeOfDay, SdAreaLight, SdDisplacement, SdDeform, SdRepeat, SdTerrain, SdInstance)>()
+        .init_asset::<SdPrototype>()
+        .insert_resource(RaymarchTextures::default())
 
         
         .insert_resource(ShapeContainer::default())
         .insert_resource(BvhTree::default())
-        .insert_resource(RaymarchSettings{
-            max_distance: 1000.0, 
-            lower_colour: vec3(0.1, 0.1, 0.2), 
-            middle_colour: vec3(0.1, 0.1, 0.4), 
-            upper_colour: vec3(0.4, 0.5, 0.75), 
-            skybox_powers: vec2(2.0, 0.6), 
-            shadow_power: 0.005,
+        .init_resource::<RaymarchSettings>()
+        .insert_resource(TimeOfDay{
+            enabled: false,
+            hour: 10.0,
+            speed: 0.0,
+            latitude: 35.0,
+        })
+        .init_resource::<RaymarchMode>()
+        .init_resource::<RaymarchQuality>()
+        .insert_resource(RaymarchResolution{
+            scale: 1.0,
+            filter: UpscaleFilter::Bilinear,
+            dynamic: false,
+            target_frame_time: 1.0 / 60.0,
+            min_scale: 0.25,
+            max_scale: 1.0,
+            frame_time: 1.0 / 60.0,
         })
         .add_systems(Startup, register_sdf_hooks.before(setup))
         .add_systems(Startup, setup)
-        .add_systems(PostUpdate, (push_shapes, window_resize).before(set_mat_values))
+        .add_systems(Update, (dynamic_resolution, apply_quality, time_of_day, apply_tonemapping))
+        .add_systems(PostUpdate, (apply_displacement, apply_deform, apply_repeat, bake_terrain, sync_prototypes, push_shapes, balance_bvh).chain().before(set_mat_values))
+        .add_systems(PostUpdate, (window_resize, sync_ray_camera, build_texture_arrays).before(set_mat_values))
         .add_systems(PostUpdate, set_mat_values)
         .insert_resource(KeyBindings {
             move_ascend: KeyCode::Space,
@@ -177,6 +225,70 @@ fn register_sdf_hooks(world: &mut World) {
             }
         }
     });
+    world.register_component_hooks::<SdFogVolume>().on_remove(|mut world, entity, _component_id|{
+        let fog_volume = world.get::<SdFogVolume>(entity).unwrap();
+        let fog_volume_index = fog_volume.index;
+        let tree = world.resource::<BvhTree>();
+        let container = world.resource::<ShapeContainer>();
+        remove_leaf(uvec2(7, fog_volume_index), container, Arc::clone(&tree.nodes), Arc::clone(&tree.node_count), Arc::clone(&tree.root_index));
+        container.fog_volumes.lock().unwrap().swap_remove(fog_volume.index as usize);
+        container.fog_volume_entities.lock().unwrap().swap_remove(fog_volume.index as usize);
+        let mut moved = None;
+        if fog_volume_index as usize != container.fog_volumes.lock().unwrap().len() {
+            let replacement = container.fog_volumes.lock().unwrap()[fog_volume.index as usize];
+            let parent = replacement.parent_idx;
+            match parent.x{
+                0 => tree.nodes.lock().unwrap()[parent.y as usize].child1 = uvec2(7, fog_volume_index),
+                _ => panic!("resetting a swapped fog volume parent failed since the type was not known"),
+            }
+            moved = Some(container.fog_volume_entities.lock().unwrap()[fog_volume_index as usize]);
+        }
+        if let Some(mut fog_volume) = moved.and_then(|moved| world.get_mut::<SdFogVolume>(moved)) {
+            fog_volume.bypass_change_detection().index = fog_volume_index;
+        }
+    });
+    world.register_component_hooks::<SdTerrain>().on_remove(|mut world, entity, _component_id|{
+        let terrain_index = world.get::<SdTerrain>(entity).unwrap().index;
+        let tree = world.resource::<BvhTree>();
+        let container = world.resource::<ShapeContainer>();
+        remove_leaf(uvec2(8, terrain_index), container, Arc::clone(&tree.nodes), Arc::clone(&tree.node_count), Arc::clone(&tree.root_index));
+        container.terrains.lock().unwrap().swap_remove(terrain_index as usize);
+        container.terrain_data.lock().unwrap().swap_remove(terrain_index as usize);
+        *container.terrain_data_changed.lock().unwrap() = true;
+        let mut moved = None;
+        if terrain_index as usize != container.terrains.lock().unwrap().len() {
+            let replacement = container.terrains.lock().unwrap()[terrain_index as usize];
+            let parent = replacement.parent_idx;
+            match parent.x{
+                0 => tree.nodes.lock().unwrap()[parent.y as usize].child1 = uvec2(8, terrain_index),
+                _ => panic!("resetting a swapped terrain parent failed since the type was not known"),
+            }
+            moved = Some(container.terrain_data.lock().unwrap()[terrain_index as usize].entity);
+        }
+        if let Some(mut terrain) = moved.and_then(|moved| world.get_mut::<SdTerrain>(moved)) {
+            terrain.bypass_change_detection().index = terrain_index;
+        }
+    });
+    world.register_component_hooks::<SdInstance>().on_remove(|mut world, entity, _component_id|{
+        let instance_index = world.get::<SdInstance>(entity).unwrap().index;
+        let tree = world.resource::<BvhTree>();
+        let container = world.resource::<ShapeContainer>();
+        remove_leaf(uvec2(9, instance_index), container, Arc::clone(&tree.nodes), Arc::clone(&tree.node_count), Arc::clone(&tree.root_index));
+        container.instances.lock().unwrap().swap_remove(instance_index as usize);
+        let mut moved = None;
+        if instance_index as usize != container.instances.lock().unwrap().len() {
+            let replacement = container.instances.lock().unwrap()[instance_index as usize];
+            let parent = replacement.parent_idx;
+            match parent.x{
+                0 => tree.nodes.lock().unwrap()[parent.y as usize].child1 = uvec2(9, instance_index),
+                _ => panic!("resetting a swapped instance parent failed since the type was not known"),
+            }
+            moved = Some(replacement.entity);
+        }
+        if let Some(mut instance) = moved.and_then(|moved| world.get_mut::<SdInstance>(moved)) {
+            instance.bypass_change_detection().index = instance_index;
+        }
+    });
 
 }
 
@@ -186,8 +298,22 @@ fn setup(
     mut commands: Commands,
     mut meshes: ResMut<Assets<Mesh>>,
     mut materials: ResMut<Assets<RaymarchMaterial>>,
+    mut upscale_materials: ResMut<Assets<UpscaleMaterial>>,
+    mut standard_materials: ResMut<Assets<StandardMaterial>>,
+    mut images: ResMut<Assets<Image>>,
+    mut textures: ResMut<RaymarchTextures>,
+    mut prototypes: ResMut<Assets<SdPrototype>>,
 ) {
 
+    // offscreen targets, resized to a fraction of the window by window_resize
+    let target = images.add(target_image(TextureUsages::RENDER_ATTACHMENT | TextureUsages::STORAGE_BINDING));
+    let resolved = images.add(target_image(TextureUsages::STORAGE_BINDING | TextureUsages::COPY_SRC));
+    let history = images.add(target_image(TextureUsages::COPY_DST));
+    let cone = images.add(cone_image(TextureUsages::STORAGE_BINDING | TextureUsages::COPY_SRC));
+    let cone_depth = images.add(cone_image(TextureUsages::COPY_DST));
+    let depth = images.add(depth_image(TextureUsages::STORAGE_BINDING | TextureUsages::COPY_SRC));
+    let history_depth = images.add(depth_image(TextureUsages::COPY_DST));
+
     // quad
     commands.spawn(MaterialMesh2dBundle {
         mesh: meshes.add(Rectangle::default()).into(),
@@ -199,35 +325,118 @@ fn setup(
             vertical: Vec3::new(0.0, 1.0, 0.0),
             fov: 90.0,
             root_index: 1,
-            raymarch_settings: RaymarchSettings{max_distance: 1000.0, ..Default::default() },
+            raymarch_settings: RaymarchSettings::default().into(),
             nodes: vec![BvhNode::default()],
             dir_lights: vec![],
             pos_lights: vec![],
+            spot_lights: vec![],
+            area_lights: vec![],
             spheres: vec![],
             cubes: vec![],
             ellipses: vec![],
             toruses: vec![],
             cylinders: vec![],
             cones: vec![],
+            fog_volumes: vec![],
+            emissive_lights: vec![],
+            jitter: Vec2::ZERO,
+            stack_size: bvh_stack_size(0),
+            mode: RaymarchMode::Direct as u32,
+            frame: 0,
+            cone_depth: cone_depth.clone(),
+            cone_prepass: 1,
+            albedo_textures: None,
+            normal_textures: None,
+            unbounded: vec![],
+            terrains: vec![],
+            terrain_heights: vec![],
+            terrain_nodes: vec![],
+            instances: vec![],
+            blas_nodes: vec![],
+            part_offsets: vec![],
+            target_size: Vec2::ONE,
+            hit_depth: depth.clone(),
         }),
         ..default()
-    }).insert((RayImage, Name::new("Quad"),));
+    }).insert((RayImage, RenderLayers::layer(1), Name::new("Quad"),));
+
+    // upscaled copy of the target drawn in the 3d pass, its vertex shader covers the screen and it writes depth
+    commands.spawn((
+        MaterialMeshBundle {
+            mesh: meshes.add(Rectangle::default()),
+            material: upscale_materials.add(UpscaleMaterial {
+                source: target.clone(),
+                filter: UpscaleFilter::Bilinear as u32,
+                max_distance: 1000.0,
+                depth: depth.clone(),
+            }),
+            ..default()
+        },
+        NoFrustumCulling,
+        NotShadowCaster,
+        RayDisplay,
+        Name::new("Display"),
+    ));
+
 
+    // target camera, hdr so the colour stays linear
+    commands.spawn((
+        Camera2dBundle {
+            camera: Camera {
+                target: RenderTarget::Image(target.clone()),
+                order: -1,
+                hdr: true,
+                ..default()
+            },
+            tonemapping: Tonemapping::None,
+            ..default()
+        },
+        RenderLayers::layer(1),
+        RayTargetCamera,
+        Name::new("Raymarch Target Camera"),
+    ));
 
-    // camera
-    commands.spawn(Camera2dBundle::default());
+    commands.insert_resource(RaymarchTarget{image: target, resolved, history, cone, cone_depth, depth, history_depth});
 
-    //raycam
+    //raycam, also the real 3d camera so meshes and raymarched shapes share a depth buffer
 
     commands.spawn((
-        SpatialBundle {
+        Camera3dBundle {
             transform: Transform::from_xyz(2.0, 1.5, -10.0).looking_at(Vec3::splat(0.0), Vec3::Y),
+            projection: PerspectiveProjection {
+                fov: 90.0_f32.to_radians(),
+                ..default()
+            }.into(),
+            camera: Camera {
+                hdr: true, //the raymarcher outputs linear hdr, apply_tonemapping brings it down to the display
+                ..default()
+            },
+            tonemapping: Tonemapping::None,
             ..Default::default()
-        }, RayCamera{fov: 90.0,},
+        }, BloomSettings::NATURAL, RayCamera{fov: 90.0,},
         FlyCam,
         Name::new("Camera"),
     ));
 
+    //a regular mesh to check that it composites with the raymarched shapes
+
+    commands.spawn((
+        PbrBundle {
+            mesh: meshes.add(Cuboid::new(1.0, 1.0, 1.0)),
+            material: standard_materials.add(Color::srgb(0.8, 0.3, 0.3)),
+            transform: Transform::from_xyz(0.0, 1.0, 0.0),
+            ..default()
+        },
+        Name::new("Mesh Cube"),
+    ));
+    commands.spawn((
+        DirectionalLightBundle {
+            transform: Transform::from_xyz(0.0, 0.0, 0.0).looking_to(Vec3::new(-0.3, -1.0, -0.2), Vec3::Y),
+            ..default()
+        },
+        Name::new("Mesh Light"),
+    ));
+
     //mainlight
 
     commands.spawn((
@@ -237,12 +446,31 @@ fn setup(
         }, SdDirectionalLight{strength: 1.0, colour: vec3(0.8, 0.75, 0.8), ..Default::default()},
         Name::new("Directional Light"),
     ));
+
+    commands.spawn((
+        SpatialBundle {
+            transform: Transform::from_xyz(-4.0, 6.0, 0.0).looking_at(vec3(-4.0, 0.0, 0.0), Vec3::Z),
+            ..Default::default()
+        }, SdSpotLight{strength: 40.0, colour: vec3(1.0, 0.9, 0.7), inner_angle: 20.0, outer_angle: 30.0, range: 20.0, ..Default::default()},
+        Name::new("Spot Light"),
+    ));
+
+    commands.spawn((
+        SpatialBundle {
+            transform: Transform::from_xyz(4.0, 5.0, -4.0).looking_at(vec3(4.0, 0.0, -4.0), Vec3::Z),
+            ..Default::default()
+        }, SdAreaLight{strength: 20.0, colour: vec3(0.7, 0.8, 1.0), shape: 0, size: vec2(3.0, 1.0), ..Default::default()},
+        Name::new("Area Light"),
+    ));
  
 
     
 
 
 
+    let (tile_albedo, tile_normal) = tile_textures(64);
+    textures.layers.push(RaymarchTexture{albedo: images.add(tile_albedo), normal: Some(images.add(tile_normal))});
+
     let a_rand = AtomicRng::with_seed(2);
 
     let bounds = 30.0;
@@ -254,10 +482,121 @@ fn setup(
         SpatialBundle {
             transform: Transform::from_xyz(0.0, 0.0, 0.0),
             ..Default::default()
-        }, SdCube{size: vec3(bounds, 0.1, bounds), colour: Vec3::splat(a_rand.f32()), ..Default::default()},
+        }, SdCube{size: vec3(bounds, 0.1, bounds), colour: Vec3::splat(a_rand.f32()), pattern: 1, pattern_scale: 0.5, pattern_colour: Vec3::splat(0.9), ..Default::default()},
         Name::new("Ground"),
     ));
 
+    commands.spawn((
+        SpatialBundle {
+            transform: Transform::from_xyz(-2.0, 1.0, 4.0),
+            ..Default::default()
+        }, SdCube{size: Vec3::splat(1.0), colour: vec3(0.9, 0.7, 0.6), texture: 1, texture_scale: 1.0, ..Default::default()},
+        Name::new("Tiled Cube"),
+    ));
+
+    commands.spawn((
+        SpatialBundle {
+            transform: Transform::from_xyz(4.0, 1.5, 0.0),
+            ..Default::default()
+        }, SdSphere{radius: 0.5, colour: vec3(1.0, 0.6, 0.2), emissive: 8.0, ..Default::default()},
+        SdEmissiveLight,
+        Name::new("Glowing Sphere"),
+    ));
+
+    commands.spawn((
+        SpatialBundle {
+            transform: Transform::from_xyz(0.0, 1.5, 6.0),
+            ..Default::default()
+        }, SdSphere{radius: 1.0, colour: vec3(0.4, 0.7, 0.5), ..Default::default()},
+        SdDisplacement{kind: 0, amplitude: 0.2, frequency: 3.0},
+        Name::new("Displaced Sphere"),
+    ));
+
+    commands.spawn((
+        SpatialBundle {
+            transform: Transform::from_xyz(3.0, 1.5, 6.0),
+            ..Default::default()
+        }, SdCube{size: vec3(0.4, 1.2, 0.4), colour: vec3(0.8, 0.5, 0.7), ..Default::default()},
+        SdDeform{twist: 1.5, ..Default::default()},
+        Name::new("Twisted Cube"),
+    ));
+
+    commands.spawn((
+        SpatialBundle {
+            transform: Transform::from_xyz(-8.0, 1.0, -8.0),
+            ..Default::default()
+        }, SdCylinder{height: 1.0, radius: 0.2, colour: vec3(0.6, 0.55, 0.5), ..Default::default()},
+        SdRepeat{spacing: vec3(1.5, 0.0, 1.5), count: Some(uvec3(4, 1, 4))},
+        Name::new("Pillars"),
+    ));
+
+    commands.spawn((
+        SpatialBundle {
+            transform: Transform::from_xyz(6.0, 1.0, 3.0),
+            ..Default::default()
+        }, SdCube{size: vec3(0.6, 0.6, 0.6), colour: vec3(0.3, 0.5, 0.9), rounding: 0.15, onion: 0.05, onion_layers: 2, ..Default::default()},
+        Name::new("Hollow Rounded Cube"),
+    ));
+
+    // hills past the far edge of the ground, baked here so the boulder can be dropped onto them
+    let hills_transform = Transform::from_xyz(0.0, -0.5, 60.0);
+    let mut hills = SdTerrain{seed: 7, ..Default::default()};
+    hills.bake(&images);
+    let boulder = vec3(4.0, 0.0, 56.0);
+    let ground = hills.height_at(&GlobalTransform::from(hills_transform), boulder).unwrap_or(0.0);
+    commands.spawn((
+        SpatialBundle {
+            transform: hills_transform,
+            ..Default::default()
+        }, hills,
+        Name::new("Hills"),
+    ));
+    commands.spawn((
+        SpatialBundle {
+            transform: Transform::from_translation(boulder.with_y(ground + 0.8)),
+            ..Default::default()
+        }, SdSphere{radius: 1.0, colour: vec3(0.5, 0.5, 0.55), ..Default::default()},
+        SdDisplacement{kind: 0, amplitude: 0.15, frequency: 2.0},
+        Name::new("Boulder"),
+    ));
+
+    // a field of crates sharing one definition, each entity only adds a transform to the gpu buffers
+    let crate_prototype = prototypes.add(SdPrototype::Cube(SdCube{size: vec3(0.3, 0.3, 0.3), colour: vec3(0.6, 0.45, 0.25), rounding: 0.03, ..Default::default()}));
+    for x in 0..10 {
+        for z in 0..10 {
+            commands.spawn((
+                SpatialBundle {
+                    transform: Transform::from_xyz(12.0 + x as f32, 0.35, -20.0 + z as f32).with_rotation(Quat::from_rotation_y((x * 10 + z) as f32 * 0.4)),
+                    ..Default::default()
+                }, SdInstance::new(crate_prototype.clone()),
+                Name::new("Instanced Crate"),
+            ));
+        }
+    }
+
+    // a 200 part spiral staircase built once in its own space, both copies are a single leaf in the main tree
+    let mut stair_parts = vec![SdPart{
+        shape: SdPrototype::Cylinder(SdCylinder{radius: 0.25, height: 5.0, colour: vec3(0.7, 0.7, 0.72), ..Default::default()}),
+        transform: Transform::from_xyz(0.0, 5.0, 0.0),
+    }];
+    for step in 0..199 {
+        let angle = step as f32 * 0.35;
+        stair_parts.push(SdPart{
+            shape: SdPrototype::Cube(SdCube{size: vec3(0.6, 0.02, 0.15), colour: vec3(0.55, 0.35, 0.2), ..Default::default()}),
+            transform: Transform::from_xyz(angle.cos() * 0.8, step as f32 * 0.05, -angle.sin() * 0.8).with_rotation(Quat::from_rotation_y(angle)),
+        });
+    }
+    let staircase = prototypes.add(SdPrototype::Compound(stair_parts));
+    for x in [-14.0, -18.0] {
+        commands.spawn((
+            SpatialBundle {
+                transform: Transform::from_xyz(x, -0.5, -14.0),
+                ..Default::default()
+            }, SdInstance::new(staircase.clone()),
+            Name::new("Spiral Staircase"),
+        ));
+    }
+
 
     for _i in 0..count{
         commands.spawn((
@@ -318,12 +657,22 @@ fn setup(
             Name::new("Cone"),
         ));
     }
+
+    // low lying haze, the shapes above it cast shafts through it
+    commands.spawn((
+        SpatialBundle {
+            transform: Transform::from_xyz(0.0, 2.0, 0.0),
+            ..Default::default()
+        }, SdFogVolume{size: vec3(bounds + 5.0, 2.0, bounds + 5.0), density: 0.08, colour: vec3(1.0, 1.0, 1.0), anisotropy: 0.4, ..Default::default()},
+        Name::new("Fog Volume"),
+    ));
 }
 
 
 
 // This is the struct that will be passed to your shader
 #[derive(Asset, TypePath, AsBindGroup, Debug, Clone)]
+#[bind_group_data(RaymarchMaterialKey)]
 struct RaymarchMaterial {
     #[uniform(1)]
     position: Vec3,
@@ -338,26 +687,81 @@ struct RaymarchMaterial {
     #[uniform(6)]
     root_index: u32,
     #[uniform(7)]
-    raymarch_settings: RaymarchSettings,
-    #[storage(8, read_only)]
+    raymarch_settings: GpuRaymarchSettings,
+    #[storage(8, read_only, visibility(fragment, compute))]
     nodes: Vec<BvhNode>,
-    #[storage(9, read_only)]
+    #[storage(9, read_only, visibility(fragment, compute))]
     dir_lights: Vec<SdDirectionalLight>,
-    #[storage(10, read_only)]
+    #[storage(10, read_only, visibility(fragment, compute))]
     pos_lights: Vec<SdPositionalLight>,
-    #[storage(11, read_only)]
+    #[storage(24, read_only, visibility(fragment, compute))]
+    spot_lights: Vec<SdSpotLight>,
+    #[storage(25, read_only, visibility(fragment, compute))]
+    area_lights: Vec<SdAreaLight>,
+    #[storage(11, read_only, visibility(fragment, compute))]
     spheres: Vec<SdSphere>,
-    #[storage(12, read_only)]
+    #[storage(12, read_only, visibility(fragment, compute))]
     cubes: Vec<SdCube>,
-    #[storage(13, read_only)]
+    #[storage(13, read_only, visibility(fragment, compute))]
     ellipses: Vec<SdEllipse>,
-    #[storage(14, read_only)]
+    #[storage(14, read_only, visibility(fragment, compute))]
     toruses: Vec<SdTorus>,
-    #[storage(15, read_only)]
+    #[storage(15, read_only, visibility(fragment, compute))]
     cylinders: Vec<SdCylinder>,
-    #[storage(16, read_only)]
+    #[storage(16, read_only, visibility(fragment, compute))]
     cones: Vec<SdCone>,
+    #[storage(22, read_only, visibility(fragment, compute))]
+    fog_volumes: Vec<SdFogVolume>,
+    #[storage(23, read_only, visibility(fragment, compute))]
+    emissive_lights: Vec<EmissiveLight>,
+    #[uniform(17)]
+    jitter: Vec2, //subpixel offset, only non zero while accumulating
+    #[uniform(18)]
+    mode: u32,
+    #[uniform(19)]
+    frame: u32, //seeds the path tracer's random numbers
+    #[texture(20, sample_type = "float", filterable = false, visibility(fragment, compute))]
+    cone_depth: Handle<Image>,
+    #[uniform(21)]
+    cone_prepass: u32,
+    // built from RaymarchTextures, the normal array shares the albedo array's sampler
+    #[texture(26, dimension = "2d_array", visibility(fragment, compute))]
+    #[sampler(27, visibility(fragment, compute))]
+    albedo_textures: Option<Handle<Image>>,
+    #[texture(28, dimension = "2d_array", visibility(fragment, compute))]
+    normal_textures: Option<Handle<Image>>,
+    #[storage(29, read_only, visibility(fragment, compute))]
+    unbounded: Vec<UVec2>, //infinitely repeated shapes, marched by every ray instead of going through the tree
+    #[storage(30, read_only, visibility(fragment, compute))]
+    terrains: Vec<TerrainShape>,
+    #[storage(31, read_only, visibility(fragment, compute))]
+    terrain_heights: Vec<f32>, //every terrain's samples back to back
+    #[storage(32, read_only, visibility(fragment, compute))]
+    terrain_nodes: Vec<BvhNode>, //every terrain's chunk tree back to back, child indices are relative to its root
+    #[storage(33, read_only, visibility(fragment, compute))]
+    instances: Vec<GpuInstance>,
+    #[storage(34, read_only, visibility(fragment, compute))]
+    blas_nodes: Vec<BvhNode>, //every compound prototype's tree back to back, child indices are relative to its root
+    #[uniform(35)]
+    target_size: Vec2, //set by window_resize
+    #[storage(36, read_only, visibility(fragment, compute))]
+    part_offsets: Vec<u32>, //per shape type, where the prototype parts start in that type's array
+    #[storage_texture(37, image_format = R32Float, access = WriteOnly, visibility(fragment, compute))]
+    hit_depth: Handle<Image>, //RaymarchTarget's depth
+    stack_size: u32, //not a binding, becomes BVH_STACK_SIZE when the pipeline is specialized
+
+}
+
+// the traversal stack is a fixed size array in the shader, so it's picked per pipeline from the tree depth
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+struct RaymarchMaterialKey {
+    stack_size: u32,
+}
 
+impl From<&RaymarchMaterial> for RaymarchMaterialKey {
+    fn from(material: &RaymarchMaterial) -> Self {
+        Self { stack_size: material.stack_size }
+    }
 }
 
 /// The Material2d trait is very configurable, but comes with sensible defaults for all methods.
@@ -366,122 +770,1350 @@ impl Material2d for RaymarchMaterial {
     fn fragment_shader() -> ShaderRef {
         "shaders/raymarch.wgsl".into()
     }
+
+    // opaque, blending would mix the raymarched colour with whatever was cleared underneath
+    fn specialize(
+        descriptor: &mut RenderPipelineDescriptor,
+        _layout: &MeshVertexBufferLayoutRef,
+        key: Material2dKey<Self>,
+    ) -> Result<(), SpecializedMeshPipelineError> {
+        if let Some(fragment) = descriptor.fragment.as_mut() {
+            for target in fragment.targets.iter_mut().flatten() {
+                target.blend = None;
+            }
+            fragment.shader_defs.push(ShaderDefVal::UInt("BVH_STACK_SIZE".into(), key.bind_group_data.stack_size));
+        }
+        Ok(())
+    }
+}
+
+// Samples the offscreen raymarch target, stretches it over the window and writes depth from the hit distance
+#[derive(Asset, TypePath, AsBindGroup, Debug, Clone)]
+struct UpscaleMaterial {
+    #[texture(0)]
+    #[sampler(1)]
+    source: Handle<Image>,
+    #[uniform(2)]
+    filter: u32,
+    #[uniform(3)]
+    max_distance: f32,
+    #[texture(4, sample_type = "float", filterable = false)]
+    depth: Handle<Image>,
+}
+
+impl Material for UpscaleMaterial {
+    fn vertex_shader() -> ShaderRef {
+        "shaders/upscale.wgsl".into()
+    }
+
+    fn fragment_shader() -> ShaderRef {
+        "shaders/upscale.wgsl".into()
+    }
+
+    // the quad is placed in clip space by the vertex shader, so it never needs culling
+    fn specialize(
+        _pipeline: &MaterialPipeline<Self>,
+        descriptor: &mut RenderPipelineDescriptor,
+        _layout: &MeshVertexBufferLayoutRef,
+        _key: MaterialPipelineKey<Self>,
+    ) -> Result<(), SpecializedMeshPipelineError> {
+        descriptor.primitive.cull_mode = None;
+        Ok(())
+    }
 }
 
 #[derive(Component)]
 struct RayImage;
 
+#[derive(Component)]
+struct RayDisplay;
+
+#[derive(Component)]
+struct RayTargetCamera;
+
+// image is what gets raymarched into, resolved is the accumulated result and history is last frame's resolved.
+// cone is written by the cone prepass and copied into cone_depth, which the raymarcher reads. depth is the hit
+// distance the raymarcher writes next to image, history_depth is last frame's
+#[derive(Resource)]
+struct RaymarchTarget{
+    image: Handle<Image>,
+    resolved: Handle<Image>,
+    history: Handle<Image>,
+    cone: Handle<Image>,
+    cone_depth: Handle<Image>,
+    depth: Handle<Image>,
+    history_depth: Handle<Image>,
+}
+
+fn target_image(usage: TextureUsages) -> Image {
+    let mut image = Image::new_fill(
+        Extent3d{width: 512, height: 512, depth_or_array_layers: 1},
+        TextureDimension::D2,
+        &[0; 8],
+        TextureFormat::Rgba16Float,
+        RenderAssetUsages::default(),
+    );
+    image.texture_descriptor.usage = TextureUsages::TEXTURE_BINDING | TextureUsages::COPY_DST | usage;
+    image.sampler = ImageSampler::linear();
+    image
+}
+
+// one texel per CONE_TILE square of the target, holding the distance the full res rays can start from
+fn cone_image(usage: TextureUsages) -> Image {
+    let mut image = Image::new_fill(
+        Extent3d{width: 64, height: 64, depth_or_array_layers: 1},
+        TextureDimension::D2,
+        &[0; 4],
+        TextureFormat::R32Float,
+        RenderAssetUsages::default(),
+    );
+    image.texture_descriptor.usage = TextureUsages::TEXTURE_BINDING | usage;
+    image
+}
+
+// full float hit distances, a 16 bit float alpha only has about three significant digits
+fn depth_image(usage: TextureUsages) -> Image {
+    let mut image = Image::new_fill(
+        Extent3d{width: 512, height: 512, depth_or_array_layers: 1},
+        TextureDimension::D2,
+        &[0; 4],
+        TextureFormat::R32Float,
+        RenderAssetUsages::default(),
+    );
+    image.texture_descriptor.usage = TextureUsages::TEXTURE_BINDING | usage;
+    image
+}
+
 #[derive(Component, Reflect)]
 pub struct RayCamera{
-    pub fov: f32,
+    pub fov: f32, //degrees, vertical, follows the Projection when there is one
 }
 
-fn window_resize(
-    windows: Query<&Window, Changed<Window>>,
-    mut ray_t_q: Query<&mut Transform, With<RayImage>>,
+fn sync_ray_camera(
+    mut ray_cam_q: Query<(&mut RayCamera, &Projection), Changed<Projection>>,
 ){
-    
-    if !windows.is_empty() {
+    for (mut raycam, projection) in &mut ray_cam_q {
+        if let Projection::Perspective(perspective) = projection {
+            raycam.fov = perspective.fov.to_degrees();
+        }
+    }
+}
+
+#[derive(Default, Debug, Clone, Copy, PartialEq, Eq, Reflect)]
+pub enum UpscaleFilter{
+    #[default]
+    Bilinear,
+    EdgeAware,
+}
+
+// the raymarcher renders at scale * window size, then gets upscaled to fill the window
+#[derive(Resource, Debug, Clone, Copy, Reflect)]
+#[reflect(Resource)]
+pub struct RaymarchResolution{
+    pub scale: f32,
+    pub filter: UpscaleFilter,
+    pub dynamic: bool, //when on, scale is driven towards target_frame_time
+    pub target_frame_time: f32, //seconds
+    pub min_scale: f32,
+    pub max_scale: f32,
+    frame_time: f32, //smoothed
+}
 
-    let window = windows.get_single().unwrap();
+fn dynamic_resolution(
+    time: Res<Time>,
+    mut resolution: ResMut<RaymarchResolution>,
+){
+    let delta = time.delta_seconds();
+    if delta <= 0.0 {
+        return;
+    }
 
-    let mut ray_t = ray_t_q.get_single_mut().unwrap();
+    let smoothed = resolution.frame_time + (delta - resolution.frame_time) * 0.1;
+    resolution.bypass_change_detection().frame_time = smoothed;
 
-    ray_t.scale = Vec3::new(window.width(), window.height(), 1.0);
+    if !resolution.dynamic {
+        return;
+    }
 
+    // pixel count scales with scale squared, so correct by the square root of the ratio
+    let ratio = (resolution.target_frame_time / smoothed).sqrt();
+    if (ratio - 1.0).abs() < 0.05 {
+        return; //close enough, avoid resizing the target every frame
+    }
+    let scale = (resolution.scale * ratio.clamp(0.95, 1.05)).clamp(resolution.min_scale, resolution.max_scale);
+    if scale != resolution.scale {
+        resolution.scale = scale;
     }
 }
 
-fn set_mat_values(
-    mut rayt: Query<(&GlobalTransform, &mut RayCamera)>,
+fn window_resize(
+    windows: Query<&Window>,
+    resolution: Res<RaymarchResolution>,
+    settings: Res<RaymarchSettings>,
+    temporal: Res<RaymarchTemporal>,
+    mode: Res<RaymarchMode>,
+    target: Res<RaymarchTarget>,
+    mut images: ResMut<Assets<Image>>,
+    mut upscale_materials: ResMut<Assets<UpscaleMaterial>>,
     mut materials: ResMut<Assets<RaymarchMaterial>>,
-    march_handle_q: Query<&mut Handle<RaymarchMaterial>, With<RayImage>>,
-    shapes_res: Res<ShapeContainer>,
-    tree_res: Res<BvhTree>,
-    settings_res: Res<RaymarchSettings>,
-    mut dir_light_q: Query<(&mut SdDirectionalLight, &GlobalTransform)>,
-    mut pos_light_q: Query<(&mut SdPositionalLight, &GlobalTransform)>,
+    display_q: Query<&Handle<UpscaleMaterial>, With<RayDisplay>>,
+    mut ray_t_q: Query<(&mut Transform, &Handle<RaymarchMaterial>), With<RayImage>>,
 ){
     
-    let mut dir_light_vec: Vec<SdDirectionalLight> = vec![];
-    let mut pos_light_vec: Vec<SdPositionalLight> = vec![];
+    let Ok(window) = windows.get_single() else {
+        return;
+    };
 
-    //do light stuff straight in the mat values
-    
-    for (mut light, gt) in &mut dir_light_q {
-        light.direction = Dir3::as_vec3(&gt.up());
-        dir_light_vec.push(*light);
+    let scale = resolution.scale.clamp(0.05, 4.0);
+    let width = ((window.physical_width() as f32 * scale) as u32).max(1);
+    let height = ((window.physical_height() as f32 * scale) as u32).max(1);
 
-    }
-    
-    for (mut light, gt) in &mut pos_light_q {
-        light.translation = gt.translation();
-        pos_light_vec.push(*light);
+    let current = images.get(&target.image).unwrap().size();
+    let resized = current != uvec2(width, height);
+    if resized {
+        for handle in [&target.image, &target.resolved, &target.history, &target.depth, &target.history_depth] {
+            images.get_mut(handle).unwrap().resize(Extent3d{width, height, depth_or_array_layers: 1});
+        }
+        let tiles = Extent3d{width: width.div_ceil(CONE_TILE), height: height.div_ceil(CONE_TILE), depth_or_array_layers: 1};
+        for handle in [&target.cone, &target.cone_depth] {
+            images.get_mut(handle).unwrap().resize(tiles);
+        }
+
+        //the target camera maps one unit to one pixel, so the quad just covers the image
+        let (mut ray_t, march_handle) = ray_t_q.get_single_mut().unwrap();
+        ray_t.scale = Vec3::new(width as f32, height as f32, 1.0);
+        if let Some(material) = materials.get_mut(march_handle) {
+            material.target_size = vec2(width as f32, height as f32);
+        }
     }
 
-    let (transform, raycam) = rayt.get_single_mut().unwrap();
+    // touching the material after a resize rebuilds its bind group, otherwise it keeps the old gpu texture
+    if resized || resolution.is_changed() || settings.is_changed() || temporal.is_changed() || mode.is_changed() {
+        let display_handle = display_q.get_single().unwrap();
+        if let Some(material) = upscale_materials.get_mut(display_handle) {
+            material.source = if temporal.accumulating(*mode) { target.resolved.clone() } else { target.image.clone() };
+            material.filter = resolution.filter as u32;
+            material.max_distance = settings.max_distance;
+        }
+    }
+}
 
-    let march_handle = march_handle_q.get_single().unwrap().id();
+// compute backend
 
-    if let Some(material) =  materials.get_mut(march_handle){
-        material.position = transform.translation();
-        material.forward = Dir3::as_vec3(&transform.forward());
-        material.horizontal = Dir3::as_vec3(&transform.right());
-        material.vertical = Dir3::as_vec3(&transform.up());
-        material.fov = raycam.fov;
-        material.root_index = *tree_res.root_index.lock().unwrap();
-        material.dir_lights = dir_light_vec;
-        material.pos_lights = pos_light_vec;
-        material.nodes = tree_res.nodes.lock().unwrap().clone();
-        material.raymarch_settings = *settings_res;
-        material.spheres = shapes_res.spheres.lock().unwrap().clone();
-        material.cubes = shapes_res.cubes.lock().unwrap().clone();
-        material.ellipses = shapes_res.ellipses.lock().unwrap().clone();
-        material.toruses = shapes_res.toruses.lock().unwrap().clone();
-        material.cylinders = shapes_res.cylinders.lock().unwrap().clone();
-        material.cones = shapes_res.cones.lock().unwrap().clone();
-    }
+// Fragment draws the material on the quad, Compute dispatches the same shader as a compute pass into the target
+#[derive(Resource, Default, Debug, Clone, Copy, PartialEq, Eq, Reflect, ExtractResource)]
+#[reflect(Resource)]
+pub enum RaymarchBackend{
+    #[default]
+    Fragment,
+    Compute,
 }
 
+struct RaymarchComputePlugin;
 
+impl Plugin for RaymarchComputePlugin {
+    fn build(&self, app: &mut App) {
+        app.init_resource::<RaymarchBackend>()
+            .add_plugins(ExtractResourcePlugin::<RaymarchBackend>::default())
+            .add_systems(PostUpdate, apply_backend);
 
-fn push_shapes(
-    container: ResMut<ShapeContainer>,
-    tree: ResMut<BvhTree>,
-    mut spheres: ParamSet<(
-        Query<(&mut SdSphere, &GlobalTransform), Added<SdSphere>>,
-        Query<(&mut SdSphere, &GlobalTransform), Or<(Changed<SdSphere>, Changed<GlobalTransform>)>>,
-    )>,
-    mut cubes: ParamSet<(
-        Query<(&mut SdCube, &GlobalTransform), Added<SdCube>>,   
-        Query<(&mut SdCube, &GlobalTransform), Or<(Changed<SdCube>, Changed<GlobalTransform>)>>,
-    )>,
-    mut ellipses: ParamSet<(
-        Query<(&mut SdEllipse, &GlobalTransform), Added<SdEllipse>>,   
-        Query<(&mut SdEllipse, &GlobalTransform), Or<(Changed<SdEllipse>, Changed<GlobalTransform>)>>,
-    )>,
-    mut toruses: ParamSet<(
-        Query<(&mut SdTorus, &GlobalTransform), Added<SdTorus>>,   
-        Query<(&mut SdTorus, &GlobalTransform), Or<(Changed<SdTorus>, Changed<GlobalTransform>)>>,
-    )>,
-    mut cylinders: ParamSet<(
-        Query<(&mut SdCylinder, &GlobalTransform), Added<SdCylinder>>,   
-        Query<(&mut SdCylinder, &GlobalTransform), Or<(Changed<SdCylinder>, Changed<GlobalTransform>)>>,
-    )>,
-    mut cones: ParamSet<(
-        Query<(&mut SdCone, &GlobalTransform), Added<SdCone>>,   
-        Query<(&mut SdCone, &GlobalTransform), Or<(Changed<SdCone>, Changed<GlobalTransform>)>>,
-    )>,
+        let Some(render_app) = app.get_sub_app_mut(RenderApp) else {
+            return;
+        };
+        render_app
+            .add_systems(ExtractSchedule, extract_compute_job)
+            .add_systems(Render, prepare_compute_bind_groups.in_set(RenderSet::PrepareBindGroups));
+
+        let mut render_graph = render_app.world_mut().resource_mut::<RenderGraph>();
+        render_graph.add_node(RaymarchComputeLabel, RaymarchComputeNode);
+        render_graph.add_node_edge(RaymarchComputeLabel, CameraDriverLabel);
+    }
 
+    fn finish(&self, app: &mut App) {
+        let Some(render_app) = app.get_sub_app_mut(RenderApp) else {
+            return;
+        };
+        render_app
+            .init_resource::<RaymarchComputePipeline>()
+            .init_resource::<SpecializedComputePipelines<RaymarchComputePipeline>>();
+    }
+}
+
+// the target camera is what draws the fragment path, so it only runs when that backend is selected
+fn apply_backend(
+    backend: Res<RaymarchBackend>,
+    mut camera_q: Query<&mut Camera, With<RayTargetCamera>>,
 ){
+    if !backend.is_changed() {
+        return;
+    }
+    for mut camera in &mut camera_q {
+        camera.is_active = *backend == RaymarchBackend::Fragment;
+    }
+}
 
+#[derive(Debug, Hash, PartialEq, Eq, Clone, RenderLabel)]
+struct RaymarchComputeLabel;
 
-    //let pushing = Instant::now();
+#[derive(Resource)]
+struct RaymarchComputePipeline {
+    output_layout: BindGroupLayout,
+    empty_layout: BindGroupLayout,
+    material_layout: BindGroupLayout,
+    shader: Handle<Shader>,
+}
 
-    spheres.p0().par_iter_mut().for_each(|(mut sphere, gt)| {
-        let transform = gt.compute_matrix();
-        sphere.transform_determinant = transform.determinant();
-        sphere.inverse_transform = transform.inverse();
+impl FromWorld for RaymarchComputePipeline {
+    fn from_world(world: &mut World) -> Self {
+        let render_device = world.resource::<RenderDevice>();
+        let output_layout = render_device.create_bind_group_layout(
+            "raymarch_output_layout",
+            &BindGroupLayoutEntries::single(
+                ShaderStages::COMPUTE,
+                texture_storage_2d(TextureFormat::Rgba16Float, StorageTextureAccess::WriteOnly),
+            ),
+        );
+        // group 1 is the view/mesh slot on the fragment path, the compute pass has nothing to put there
+        let empty_layout = render_device.create_bind_group_layout("raymarch_empty_layout", &[]);
+        let material_layout = RaymarchMaterial::bind_group_layout(render_device);
+
+        let shader = world.resource::<AssetServer>().load("shaders/raymarch.wgsl");
+
+        Self { output_layout, empty_layout, material_layout, shader }
+    }
+}
+
+// same key as the fragment path, so both backends size the traversal stack the same way
+impl SpecializedComputePipeline for RaymarchComputePipeline {
+    type Key = RaymarchMaterialKey;
+
+    fn specialize(&self, key: Self::Key) -> ComputePipelineDescriptor {
+        ComputePipelineDescriptor {
+            label: Some("raymarch_compute_pipeline".into()),
+            layout: vec![self.output_layout.clone(), self.empty_layout.clone(), self.material_layout.clone()],
+            push_constant_ranges: vec![],
+            shader: self.shader.clone(),
+            shader_defs: vec![
+                "RAYMARCH_COMPUTE".into(),
+                ShaderDefVal::UInt("BVH_STACK_SIZE".into(), key.stack_size),
+            ],
+            entry_point: Cow::from("main"),
+        }
+    }
+}
+
+// what the compute node needs from the main world, only present when the compute backend is selected
+#[derive(Resource)]
+struct RaymarchComputeJob {
+    target: AssetId<Image>,
+    material: AssetId<RaymarchMaterial>,
+}
+
+#[derive(Resource)]
+struct RaymarchComputeBindGroups {
+    output: BindGroup,
+    empty: BindGroup,
+    material: BindGroup,
+    size: UVec2,
+    pipeline: CachedComputePipelineId,
+}
+
+fn extract_compute_job(
+    mut commands: Commands,
+    backend: Extract<Res<RaymarchBackend>>,
+    target: Extract<Option<Res<RaymarchTarget>>>,
+    material_q: Extract<Query<&Handle<RaymarchMaterial>, With<RayImage>>>,
+){
+    let (Some(target), Ok(material)) = (target.as_ref(), material_q.get_single()) else {
+        commands.remove_resource::<RaymarchComputeJob>();
+        return;
+    };
+    if **backend != RaymarchBackend::Compute {
+        commands.remove_resource::<RaymarchComputeJob>();
+        return;
+    }
+    commands.insert_resource(RaymarchComputeJob {
+        target: target.image.id(),
+        material: material.id(),
+    });
+}
+
+fn prepare_compute_bind_groups(
+    mut commands: Commands,
+    job: Option<Res<RaymarchComputeJob>>,
+    pipeline: Res<RaymarchComputePipeline>,
+    mut pipelines: ResMut<SpecializedComputePipelines<RaymarchComputePipeline>>,
+    pipeline_cache: Res<PipelineCache>,
+    gpu_images: Res<RenderAssets<GpuImage>>,
+    materials: Res<RenderAssets<PreparedMaterial2d<RaymarchMaterial>>>,
+    render_device: Res<RenderDevice>,
+){
+    let Some(job) = job else {
+        commands.remove_resource::<RaymarchComputeBindGroups>();
+        return;
+    };
+    let (Some(target), Some(material)) = (gpu_images.get(job.target), materials.get(job.material)) else {
+        commands.remove_resource::<RaymarchComputeBindGroups>();
+        return;
+    };
+
+    let output = render_device.create_bind_group(
+        "raymarch_output_bind_group",
+        &pipeline.output_layout,
+        &BindGroupEntries::single(&target.texture_view),
+    );
+    let empty = render_device.create_bind_group("raymarch_empty_bind_group", &pipeline.empty_layout, &[]);
+
+    commands.insert_resource(RaymarchComputeBindGroups {
+        output,
+        empty,
+        material: material.bind_group.clone(),
+        size: target.size,
+        pipeline: pipelines.specialize(&pipeline_cache, &pipeline, material.key),
+    });
+}
+
+struct RaymarchComputeNode;
+
+impl render_graph::Node for RaymarchComputeNode {
+    fn run(
+        &self,
+        _graph: &mut render_graph::RenderGraphContext,
+        render_context: &mut RenderContext,
+        world: &World,
+    ) -> Result<(), render_graph::NodeRunError> {
+        let Some(bind_groups) = world.get_resource::<RaymarchComputeBindGroups>() else {
+            return Ok(());
+        };
+        let pipeline_cache = world.resource::<PipelineCache>();
+        let Some(pipeline) = pipeline_cache.get_compute_pipeline(bind_groups.pipeline) else {
+            return Ok(()); //still compiling
+        };
+
+        let mut pass = render_context
+            .command_encoder()
+            .begin_compute_pass(&ComputePassDescriptor { label: Some("raymarch_compute_pass"), timestamp_writes: None });
+
+        pass.set_pipeline(pipeline);
+        pass.set_bind_group(0, &bind_groups.output, &[]);
+        pass.set_bind_group(1, &bind_groups.empty, &[]);
+        pass.set_bind_group(2, &bind_groups.material, &[]);
+        pass.dispatch_workgroups(bind_groups.size.x.div_ceil(8), bind_groups.size.y.div_ceil(8), 1);
+
+        Ok(())
+    }
+}
+
+// cone prepass
+
+// marches one widening cone per tile first, the full res rays then start from the closest distance that cone could reach
+#[derive(Resource, Debug, Clone, Copy, Reflect, ExtractResource)]
+#[reflect(Resource)]
+pub struct RaymarchConePrepass{
+    pub enabled: bool,
+}
+
+// pixels per side of a prepass tile, also the workgroup size so the shader can assume it
+const CONE_TILE: u32 = 8;
+
+struct RaymarchConePlugin;
+
+impl Plugin for RaymarchConePlugin {
+    fn build(&self, app: &mut App) {
+        app.insert_resource(RaymarchConePrepass{enabled: true})
+            .add_plugins(ExtractResourcePlugin::<RaymarchConePrepass>::default());
+
+        let Some(render_app) = app.get_sub_app_mut(RenderApp) else {
+            return;
+        };
+        render_app
+            .add_systems(ExtractSchedule, extract_cone_job)
+            .add_systems(Render, prepare_cone_bind_groups.in_set(RenderSet::PrepareBindGroups));
+
+        let mut render_graph = render_app.world_mut().resource_mut::<RenderGraph>();
+        render_graph.add_node(RaymarchConeLabel, RaymarchConeNode);
+        render_graph.add_node_edge(RaymarchConeLabel, RaymarchComputeLabel);
+        render_graph.add_node_edge(RaymarchConeLabel, CameraDriverLabel);
+    }
+
+    fn finish(&self, app: &mut App) {
+        let Some(render_app) = app.get_sub_app_mut(RenderApp) else {
+            return;
+        };
+        render_app
+            .init_resource::<RaymarchConePipeline>()
+            .init_resource::<SpecializedComputePipelines<RaymarchConePipeline>>();
+    }
+}
+
+#[derive(Debug, Hash, PartialEq, Eq, Clone, RenderLabel)]
+struct RaymarchConeLabel;
+
+#[derive(Resource)]
+struct RaymarchConePipeline {
+    output_layout: BindGroupLayout,
+    empty_layout: BindGroupLayout,
+    material_layout: BindGroupLayout,
+    shader: Handle<Shader>,
+}
+
+impl FromWorld for RaymarchConePipeline {
+    fn from_world(world: &mut World) -> Self {
+        let render_device = world.resource::<RenderDevice>();
+        let output_layout = render_device.create_bind_group_layout(
+            "raymarch_cone_output_layout",
+            &BindGroupLayoutEntries::single(
+                ShaderStages::COMPUTE,
+                texture_storage_2d(TextureFormat::R32Float, StorageTextureAccess::WriteOnly),
+            ),
+        );
+        let empty_layout = render_device.create_bind_group_layout("raymarch_cone_empty_layout", &[]);
+        let material_layout = RaymarchMaterial::bind_group_layout(render_device);
+        let shader = world.resource::<AssetServer>().load("shaders/raymarch.wgsl");
+
+        Self { output_layout, empty_layout, material_layout, shader }
+    }
+}
+
+impl SpecializedComputePipeline for RaymarchConePipeline {
+    type Key = RaymarchMaterialKey;
+
+    fn specialize(&self, key: Self::Key) -> ComputePipelineDescriptor {
+        ComputePipelineDescriptor {
+            label: Some("raymarch_cone_pipeline".into()),
+            layout: vec![self.output_layout.clone(), self.empty_layout.clone(), self.material_layout.clone()],
+            push_constant_ranges: vec![],
+            shader: self.shader.clone(),
+            shader_defs: vec![
+                "RAYMARCH_COMPUTE".into(),
+                "RAYMARCH_CONE".into(),
+                ShaderDefVal::UInt("BVH_STACK_SIZE".into(), key.stack_size),
+            ],
+            entry_point: Cow::from("cone_main"),
+        }
+    }
+}
+
+// the material binds cone_depth for reading, so the pass writes cone and copies it over afterwards
+#[derive(Resource)]
+struct RaymarchConeJob {
+    cone: AssetId<Image>,
+    cone_depth: AssetId<Image>,
+    material: AssetId<RaymarchMaterial>,
+}
+
+#[derive(Resource)]
+struct RaymarchConeBindGroups {
+    output: BindGroup,
+    empty: BindGroup,
+    material: BindGroup,
+    cone: Texture,
+    cone_depth: Texture,
+    size: UVec2,
+    pipeline: CachedComputePipelineId,
+}
+
+fn extract_cone_job(
+    mut commands: Commands,
+    cone_prepass: Extract<Res<RaymarchConePrepass>>,
+    target: Extract<Option<Res<RaymarchTarget>>>,
+    material_q: Extract<Query<&Handle<RaymarchMaterial>, With<RayImage>>>,
+){
+    match (target.as_ref(), material_q.get_single()) {
+        (Some(target), Ok(material)) if cone_prepass.enabled => commands.insert_resource(RaymarchConeJob {
+            cone: target.cone.id(),
+            cone_depth: target.cone_depth.id(),
+            material: material.id(),
+        }),
+        _ => commands.remove_resource::<RaymarchConeJob>(),
+    }
+}
+
+fn prepare_cone_bind_groups(
+    mut commands: Commands,
+    job: Option<Res<RaymarchConeJob>>,
+    pipeline: Res<RaymarchConePipeline>,
+    mut pipelines: ResMut<SpecializedComputePipelines<RaymarchConePipeline>>,
+    pipeline_cache: Res<PipelineCache>,
+    gpu_images: Res<RenderAssets<GpuImage>>,
+    materials: Res<RenderAssets<PreparedMaterial2d<RaymarchMaterial>>>,
+    render_device: Res<RenderDevice>,
+){
+    let Some(job) = job else {
+        commands.remove_resource::<RaymarchConeBindGroups>();
+        return;
+    };
+    let (Some(cone), Some(cone_depth), Some(material)) = (gpu_images.get(job.cone), gpu_images.get(job.cone_depth), materials.get(job.material)) else {
+        commands.remove_resource::<RaymarchConeBindGroups>();
+        return;
+    };
+
+    let output = render_device.create_bind_group(
+        "raymarch_cone_output_bind_group",
+        &pipeline.output_layout,
+        &BindGroupEntries::single(&cone.texture_view),
+    );
+    let empty = render_device.create_bind_group("raymarch_cone_empty_bind_group", &pipeline.empty_layout, &[]);
+
+    commands.insert_resource(RaymarchConeBindGroups {
+        output,
+        empty,
+        material: material.bind_group.clone(),
+        cone: cone.texture.clone(),
+        cone_depth: cone_depth.texture.clone(),
+        size: cone.size,
+        pipeline: pipelines.specialize(&pipeline_cache, &pipeline, material.key),
+    });
+}
+
+struct RaymarchConeNode;
+
+impl render_graph::Node for RaymarchConeNode {
+    fn run(
+        &self,
+        _graph: &mut render_graph::RenderGraphContext,
+        render_context: &mut RenderContext,
+        world: &World,
+    ) -> Result<(), render_graph::NodeRunError> {
+        let Some(bind_groups) = world.get_resource::<RaymarchConeBindGroups>() else {
+            return Ok(());
+        };
+        let pipeline_cache = world.resource::<PipelineCache>();
+        let Some(pipeline) = pipeline_cache.get_compute_pipeline(bind_groups.pipeline) else {
+            return Ok(());
+        };
+
+        {
+            let mut pass = render_context
+                .command_encoder()
+                .begin_compute_pass(&ComputePassDescriptor { label: Some("raymarch_cone_pass"), timestamp_writes: None });
+
+            pass.set_pipeline(pipeline);
+            pass.set_bind_group(0, &bind_groups.output, &[]);
+            pass.set_bind_group(1, &bind_groups.empty, &[]);
+            pass.set_bind_group(2, &bind_groups.material, &[]);
+            pass.dispatch_workgroups(bind_groups.size.x.div_ceil(8), bind_groups.size.y.div_ceil(8), 1);
+        }
+
+        render_context.command_encoder().copy_texture_to_texture(
+            bind_groups.cone.as_image_copy(),
+            bind_groups.cone_depth.as_image_copy(),
+            Extent3d{width: bind_groups.size.x, height: bind_groups.size.y, depth_or_array_layers: 1},
+        );
+
+        Ok(())
+    }
+}
+
+// temporal accumulation
+
+// blends each raymarched frame into a reprojected history, which the display samples instead of the raw target
+#[derive(Resource, Debug, Clone, Copy, Reflect)]
+#[reflect(Resource)]
+pub struct RaymarchTemporal{
+    pub enabled: bool,
+    pub blend: f32, //weight of the new frame
+    pub rejection: f32, //relative depth difference that counts as a disocclusion
+}
+
+impl RaymarchTemporal {
+    // path tracing always accumulates, it's unusable otherwise
+    fn accumulating(&self, mode: RaymarchMode) -> bool {
+        self.enabled || mode == RaymarchMode::PathTraced
+    }
+}
+
+struct RaymarchTemporalPlugin;
+
+impl Plugin for RaymarchTemporalPlugin {
+    fn build(&self, app: &mut App) {
+        app.insert_resource(RaymarchTemporal{enabled: false, blend: 0.1, rejection: 0.05})
+            .init_resource::<TemporalState>()
+            .init_resource::<TemporalUniform>()
+            .add_plugins(ExtractResourcePlugin::<TemporalUniform>::default())
+            .add_systems(PostUpdate, update_temporal.after(set_mat_values));
+
+        let Some(render_app) = app.get_sub_app_mut(RenderApp) else {
+            return;
+        };
+        render_app
+            .init_resource::<TemporalUniformBuffer>()
+            .add_systems(ExtractSchedule, extract_temporal_job)
+            .add_systems(Render, prepare_temporal_bind_group.in_set(RenderSet::PrepareBindGroups))
+            .add_render_graph_node::<TemporalNode>(Core3d, TemporalLabel)
+            .add_render_graph_edges(Core3d, (Node3d::EndPrepasses, TemporalLabel, Node3d::StartMainPass));
+    }
+
+    fn finish(&self, app: &mut App) {
+        let Some(render_app) = app.get_sub_app_mut(RenderApp) else {
+            return;
+        };
+        render_app.init_resource::<TemporalPipeline>();
+    }
+}
+
+#[derive(Resource, Default, Debug, Clone, Copy, ShaderType, ExtractResource)]
+struct TemporalUniform {
+    position: Vec3,
+    forward: Vec3,
+    horizontal: Vec3,
+    vertical: Vec3,
+    prev_position: Vec3,
+    prev_forward: Vec3,
+    prev_horizontal: Vec3,
+    prev_vertical: Vec3,
+    scale: f32, //tan(fov / 2)
+    prev_scale: f32,
+    aspect: f32,
+    max_distance: f32,
+    blend: f32,
+    rejection: f32,
+    reset: u32,
+    progressive: u32, //camera is still, average every frame equally instead of reprojecting
+    jitter: Vec2, //the subpixel offset the current frame was raymarched with
+}
+
+#[derive(Resource, Default)]
+struct TemporalState {
+    shape_count: usize,
+    size: UVec2,
+    primed: bool,
+    accumulated: u32, //frames averaged so far in progressive mode
+}
+
+type ShapeFilter = Or<(With<SdSphere>, With<SdCube>, With<SdEllipse>, With<SdTorus>, With<SdCylinder>, With<SdCone>, With<SdFogVolume>, With<SdTerrain>, With<SdInstance>)>;
+
+// history is thrown away whenever the scene itself changes, camera motion is handled by reprojection
+fn update_temporal(
+    temporal: Res<RaymarchTemporal>,
+    mode: Res<RaymarchMode>,
+    settings: Res<RaymarchSettings>,
+    target: Res<RaymarchTarget>,
+    images: Res<Assets<Image>>,
+    shapes_res: Res<ShapeContainer>,
+    mut state: ResMut<TemporalState>,
+    mut uniform: ResMut<TemporalUniform>,
+    camera_q: Query<(&GlobalTransform, &RayCamera)>,
+    frame: Res<FrameCount>,
+    changed_shapes: Query<(), Or<(Changed<SdSphere>, Changed<SdCube>, Changed<SdEllipse>, Changed<SdTorus>, Changed<SdCylinder>, Changed<SdCone>, Changed<SdFogVolume>, Changed<SdTerrain>, Changed<SdInstance>)>>,
+    moved_shapes: Query<(), (Changed<GlobalTransform>, ShapeFilter)>,
+){
+    let Ok((transform, raycam)) = camera_q.get_single() else {
+        return;
+    };
+
+    let shape_count = shapes_res.spheres.lock().unwrap().len()
+        + shapes_res.cubes.lock().unwrap().len()
+        + shapes_res.ellipses.lock().unwrap().len()
+        + shapes_res.toruses.lock().unwrap().len()
+        + shapes_res.cylinders.lock().unwrap().len()
+        + shapes_res.cones.lock().unwrap().len()
+        + shapes_res.fog_volumes.lock().unwrap().len()
+        + shapes_res.terrains.lock().unwrap().len()
+        + shapes_res.instances.lock().unwrap().len();
+    let size = images.get(&target.image).map(|image| image.size()).unwrap_or_default();
+
+    let accumulating = temporal.accumulating(*mode);
+    let progressive = *mode == RaymarchMode::PathTraced;
+
+    let position = transform.translation();
+    let forward = Dir3::as_vec3(&transform.forward());
+    let horizontal = Dir3::as_vec3(&transform.right());
+    let scale = (raycam.fov.to_radians() / 2.0).tan();
+    let previous = *uniform;
+    // the whole basis, a roll around forward moves every pixel too
+    let camera_moved = position.distance_squared(previous.position) > 1e-10
+        || forward.dot(previous.forward) < 1.0 - 1e-7
+        || horizontal.dot(previous.horizontal) < 1.0 - 1e-7
+        || scale != previous.scale;
+
+    let reset = !state.primed
+        || !accumulating
+        || temporal.is_changed()
+        || mode.is_changed()
+        || settings.is_changed()
+        || !changed_shapes.is_empty()
+        || !moved_shapes.is_empty()
+        || shape_count != state.shape_count
+        || size != state.size
+        || (progressive && camera_moved);
+
+    state.primed = accumulating;
+    state.shape_count = shape_count;
+    state.size = size;
+    state.accumulated = if reset { 1 } else { state.accumulated.saturating_add(1) };
+
+    let blend = if progressive {
+        1.0 / state.accumulated as f32
+    } else {
+        temporal.blend.clamp(0.0, 1.0)
+    };
+
+    // last frame's camera becomes the previous one before it gets overwritten
+    *uniform = TemporalUniform {
+        position,
+        forward,
+        horizontal,
+        vertical: Dir3::as_vec3(&transform.up()),
+        prev_position: previous.position,
+        prev_forward: previous.forward,
+        prev_horizontal: previous.horizontal,
+        prev_vertical: previous.vertical,
+        scale,
+        prev_scale: previous.scale,
+        aspect: size.x as f32 / size.y.max(1) as f32,
+        max_distance: settings.max_distance,
+        blend,
+        rejection: temporal.rejection,
+        reset: reset as u32,
+        progressive: progressive as u32,
+        jitter: if accumulating { frame_jitter(frame.0) } else { Vec2::ZERO },
+    };
+}
+
+#[derive(Debug, Hash, PartialEq, Eq, Clone, RenderLabel)]
+struct TemporalLabel;
+
+#[derive(Resource)]
+struct TemporalPipeline {
+    layout: BindGroupLayout,
+    pipeline: CachedComputePipelineId,
+}
+
+impl FromWorld for TemporalPipeline {
+    fn from_world(world: &mut World) -> Self {
+        let render_device = world.resource::<RenderDevice>();
+        let layout = render_device.create_bind_group_layout(
+            "raymarch_temporal_layout",
+            &BindGroupLayoutEntries::sequential(
+                ShaderStages::COMPUTE,
+                (
+                    texture_2d(TextureSampleType::Float { filterable: true }),
+                    texture_2d(TextureSampleType::Float { filterable: true }),
+                    sampler(SamplerBindingType::Filtering),
+                    texture_storage_2d(TextureFormat::Rgba16Float, StorageTextureAccess::WriteOnly),
+                    uniform_buffer::<TemporalUniform>(false),
+                    texture_2d(TextureSampleType::Float { filterable: false }),
+                    texture_2d(TextureSampleType::Float { filterable: false }),
+                ),
+            ),
+        );
+
+        let shader = world.resource::<AssetServer>().load("shaders/accumulate.wgsl");
+        let pipeline = world.resource::<PipelineCache>().queue_compute_pipeline(ComputePipelineDescriptor {
+            label: Some("raymarch_temporal_pipeline".into()),
+            layout: vec![layout.clone()],
+            push_constant_ranges: vec![],
+            shader,
+            shader_defs: vec![],
+            entry_point: Cow::from("main"),
+        });
+
+        Self { layout, pipeline }
+    }
+}
+
+#[derive(Resource)]
+struct TemporalJob {
+    image: AssetId<Image>,
+    resolved: AssetId<Image>,
+    history: AssetId<Image>,
+    depth: AssetId<Image>,
+    history_depth: AssetId<Image>,
+}
+
+#[derive(Resource, Default)]
+struct TemporalUniformBuffer(UniformBuffer<TemporalUniform>);
+
+#[derive(Resource)]
+struct TemporalBindGroup {
+    bind_group: BindGroup,
+    resolved: Texture,
+    history: Texture,
+    depth: Texture,
+    history_depth: Texture,
+    size: UVec2,
+}
+
+fn extract_temporal_job(
+    mut commands: Commands,
+    temporal: Extract<Res<RaymarchTemporal>>,
+    mode: Extract<Res<RaymarchMode>>,
+    target: Extract<Option<Res<RaymarchTarget>>>,
+){
+    match target.as_ref() {
+        Some(target) if temporal.accumulating(**mode) => commands.insert_resource(TemporalJob {
+            image: target.image.id(),
+            resolved: target.resolved.id(),
+            history: target.history.id(),
+            depth: target.depth.id(),
+            history_depth: target.history_depth.id(),
+        }),
+        _ => commands.remove_resource::<TemporalJob>(),
+    }
+}
+
+fn prepare_temporal_bind_group(
+    mut commands: Commands,
+    job: Option<Res<TemporalJob>>,
+    pipeline: Res<TemporalPipeline>,
+    uniform: Res<TemporalUniform>,
+    mut uniform_buffer: ResMut<TemporalUniformBuffer>,
+    gpu_images: Res<RenderAssets<GpuImage>>,
+    render_device: Res<RenderDevice>,
+    render_queue: Res<RenderQueue>,
+){
+    let Some(job) = job else {
+        commands.remove_resource::<TemporalBindGroup>();
+        return;
+    };
+    let (Some(image), Some(resolved), Some(history)) = (gpu_images.get(job.image), gpu_images.get(job.resolved), gpu_images.get(job.history)) else {
+        commands.remove_resource::<TemporalBindGroup>();
+        return;
+    };
+    let (Some(depth), Some(history_depth)) = (gpu_images.get(job.depth), gpu_images.get(job.history_depth)) else {
+        commands.remove_resource::<TemporalBindGroup>();
+        return;
+    };
+    if image.size != resolved.size || image.size != history.size || image.size != depth.size || image.size != history_depth.size {
+        commands.remove_resource::<TemporalBindGroup>(); //mid resize
+        return;
+    }
+
+    uniform_buffer.0.set(*uniform);
+    uniform_buffer.0.write_buffer(&render_device, &render_queue);
+    let Some(uniform_binding) = uniform_buffer.0.binding() else {
+        return;
+    };
+
+    let bind_group = render_device.create_bind_group(
+        "raymarch_temporal_bind_group",
+        &pipeline.layout,
+        &BindGroupEntries::sequential((
+            &image.texture_view,
+            &history.texture_view,
+            &history.sampler,
+            &resolved.texture_view,
+            uniform_binding,
+            &depth.texture_view,
+            &history_depth.texture_view,
+        )),
+    );
+
+    commands.insert_resource(TemporalBindGroup {
+        bind_group,
+        resolved: resolved.texture.clone(),
+        history: history.texture.clone(),
+        depth: depth.texture.clone(),
+        history_depth: history_depth.texture.clone(),
+        size: image.size,
+    });
+}
+
+#[derive(Default)]
+struct TemporalNode;
+
+impl render_graph::Node for TemporalNode {
+    fn run(
+        &self,
+        _graph: &mut render_graph::RenderGraphContext,
+        render_context: &mut RenderContext,
+        world: &World,
+    ) -> Result<(), render_graph::NodeRunError> {
+        let Some(bind_group) = world.get_resource::<TemporalBindGroup>() else {
+            return Ok(());
+        };
+        let pipeline_cache = world.resource::<PipelineCache>();
+        let Some(pipeline) = pipeline_cache.get_compute_pipeline(world.resource::<TemporalPipeline>().pipeline) else {
+            return Ok(());
+        };
+
+        {
+            let mut pass = render_context
+                .command_encoder()
+                .begin_compute_pass(&ComputePassDescriptor { label: Some("raymarch_temporal_pass"), timestamp_writes: None });
+
+            pass.set_pipeline(pipeline);
+            pass.set_bind_group(0, &bind_group.bind_group, &[]);
+            pass.dispatch_workgroups(bind_group.size.x.div_ceil(8), bind_group.size.y.div_ceil(8), 1);
+        }
+
+        // resolved becomes next frame's history
+        let size = Extent3d{width: bind_group.size.x, height: bind_group.size.y, depth_or_array_layers: 1};
+        render_context.command_encoder().copy_texture_to_texture(
+            bind_group.resolved.as_image_copy(),
+            bind_group.history.as_image_copy(),
+            size,
+        );
+        render_context.command_encoder().copy_texture_to_texture(
+            bind_group.depth.as_image_copy(),
+            bind_group.history_depth.as_image_copy(),
+            size,
+        );
+
+        Ok(())
+    }
+}
+
+fn set_mat_values(
+    mut rayt: Query<(&GlobalTransform, &mut RayCamera)>,
+    mut materials: ResMut<Assets<RaymarchMaterial>>,
+    march_handle_q: Query<&mut Handle<RaymarchMaterial>, With<RayImage>>,
+    shapes_res: Res<ShapeContainer>,
+    tree_res: Res<BvhTree>,
+    settings_res: Res<RaymarchSettings>,
+    temporal: Res<RaymarchTemporal>,
+    mode: Res<RaymarchMode>,
+    frame: Res<FrameCount>,
+    cone_prepass: Res<RaymarchConePrepass>,
+    mut dir_light_q: Query<(&mut SdDirectionalLight, &GlobalTransform)>,
+    mut pos_light_q: Query<(&mut SdPositionalLight, &GlobalTransform)>,
+    mut spot_light_q: Query<(&mut SdSpotLight, &GlobalTransform)>,
+    mut area_light_q: Query<(&mut SdAreaLight, &GlobalTransform)>,
+    emissive_q: Query<(&GlobalTransform, AnyOf<(&SdSphere, &SdCube, &SdEllipse, &SdTorus, &SdCylinder, &SdCone)>), With<SdEmissiveLight>>,
+){
+    
+    let mut dir_light_vec: Vec<SdDirectionalLight> = vec![];
+    let mut pos_light_vec: Vec<SdPositionalLight> = vec![];
+    let mut spot_light_vec: Vec<SdSpotLight> = vec![];
+    let mut area_light_vec: Vec<SdAreaLight> = vec![];
+
+    //do light stuff straight in the mat values
+    
+    for (mut light, gt) in &mut dir_light_q {
+        light.direction = Dir3::as_vec3(&gt.up());
+        dir_light_vec.push(*light);
+
+    }
+    
+    for (mut light, gt) in &mut pos_light_q {
+        light.translation = gt.translation();
+        pos_light_vec.push(*light);
+    }
+
+    for (mut light, gt) in &mut spot_light_q {
+        light.translation = gt.translation();
+        light.direction = Dir3::as_vec3(&gt.forward());
+        spot_light_vec.push(*light);
+    }
+
+    for (mut light, gt) in &mut area_light_q {
+        light.translation = gt.translation();
+        light.right = Dir3::as_vec3(&gt.right());
+        light.up = Dir3::as_vec3(&gt.up());
+        light.normal = Dir3::as_vec3(&gt.forward());
+        area_light_vec.push(*light);
+    }
+
+    let mut emissive_light_vec: Vec<EmissiveLight> = vec![];
+    for (gt, shape) in &emissive_q {
+        let (colour, emissive, extent) = match shape {
+            (Some(sphere), ..) => (sphere.colour, sphere.emissive, sphere.radius),
+            (_, Some(cube), ..) => (cube.colour, cube.emissive, cube.size.length()),
+            (_, _, Some(ellipse), ..) => (ellipse.colour, ellipse.emissive, ellipse.radii.max_element()),
+            (_, _, _, Some(torus), ..) => (torus.colour, torus.emissive, torus.radii.x + torus.radii.y),
+            (_, _, _, _, Some(cylinder), _) => (cylinder.colour, cylinder.emissive, vec2(cylinder.radius, cylinder.height).length()),
+            (_, _, _, _, _, Some(cone)) => (cone.colour, cone.emissive, cone.height),
+            _ => continue,
+        };
+        if emissive <= 0.0 {
+            continue;
+        }
+        let (scale, _, translation) = gt.to_scale_rotation_translation();
+        emissive_light_vec.push(EmissiveLight{
+            position: translation,
+            radius: extent * scale.max_element(),
+            colour: colour * emissive,
+        });
+    }
+
+    let (transform, raycam) = rayt.get_single_mut().unwrap();
+
+    let march_handle = march_handle_q.get_single().unwrap().id();
+
+    if let Some(material) =  materials.get_mut(march_handle){
+        material.position = transform.translation();
+        material.forward = Dir3::as_vec3(&transform.forward());
+        material.horizontal = Dir3::as_vec3(&transform.right());
+        material.vertical = Dir3::as_vec3(&transform.up());
+        material.fov = raycam.fov;
+        material.root_index = *tree_res.root_index.lock().unwrap();
+        material.dir_lights = dir_light_vec;
+        material.pos_lights = pos_light_vec;
+        material.spot_lights = spot_light_vec;
+        material.area_lights = area_light_vec;
+        material.emissive_lights = emissive_light_vec;
+        material.nodes = tree_res.nodes.lock().unwrap().clone();
+        material.raymarch_settings = (*settings_res).into();
+        material.spheres = shapes_res.spheres.lock().unwrap().clone();
+        material.cubes = shapes_res.cubes.lock().unwrap().clone();
+        material.ellipses = shapes_res.ellipses.lock().unwrap().clone();
+        material.toruses = shapes_res.toruses.lock().unwrap().clone();
+        material.cylinders = shapes_res.cylinders.lock().unwrap().clone();
+        material.cones = shapes_res.cones.lock().unwrap().clone();
+        material.fog_volumes = shapes_res.fog_volumes.lock().unwrap().clone();
+
+        let mut unbounded = vec![];
+        unbounded.extend(material.spheres.iter().enumerate().filter(|(_, s)| s.repeat.mode == 2).map(|(i, _)| uvec2(1, i as u32)));
+        unbounded.extend(material.cubes.iter().enumerate().filter(|(_, s)| s.repeat.mode == 2).map(|(i, _)| uvec2(2, i as u32)));
+        unbounded.extend(material.ellipses.iter().enumerate().filter(|(_, s)| s.repeat.mode == 2).map(|(i, _)| uvec2(3, i as u32)));
+        unbounded.extend(material.toruses.iter().enumerate().filter(|(_, s)| s.repeat.mode == 2).map(|(i, _)| uvec2(4, i as u32)));
+        unbounded.extend(material.cylinders.iter().enumerate().filter(|(_, s)| s.repeat.mode == 2).map(|(i, _)| uvec2(5, i as u32)));
+        unbounded.extend(material.cones.iter().enumerate().filter(|(_, s)| s.repeat.mode == 2).map(|(i, _)| uvec2(6, i as u32)));
+        material.unbounded = unbounded;
+
+        // the packed prototype parts go after the scene's own shapes, the shader adds these to their slots
+        let mut parts = shapes_res.prototype_parts.lock().unwrap();
+        material.part_offsets = vec![
+            0,
+            material.spheres.len() as u32,
+            material.cubes.len() as u32,
+            material.ellipses.len() as u32,
+            material.toruses.len() as u32,
+            material.cylinders.len() as u32,
+            material.cones.len() as u32,
+        ];
+        material.spheres.extend_from_slice(&parts.spheres);
+        material.cubes.extend_from_slice(&parts.cubes);
+        material.ellipses.extend_from_slice(&parts.ellipses);
+        material.toruses.extend_from_slice(&parts.toruses);
+        material.cylinders.extend_from_slice(&parts.cylinders);
+        material.cones.extend_from_slice(&parts.cones);
+        if std::mem::take(&mut parts.changed) {
+            material.blas_nodes = parts.blas_nodes.clone();
+        }
+        let prototype_slots = &parts.slots;
+        material.instances = shapes_res.instances.lock().unwrap().iter().map(|instance| GpuInstance{
+            shape: prototype_slots.get(&instance.prototype).copied().unwrap_or(UVec2::ZERO), //type 0 reads as empty in map
+            transform_determinant: instance.transform_determinant,
+            inverse_transform: instance.inverse_transform,
+        }).collect();
+        drop(parts);
+
+        let mut terrains = shapes_res.terrains.lock().unwrap().clone();
+        let terrain_data = shapes_res.terrain_data.lock().unwrap();
+        let (mut height_offset, mut node_offset) = (0, 0);
+        for (terrain, data) in terrains.iter_mut().zip(terrain_data.iter()) {
+            terrain.height_offset = height_offset;
+            terrain.node_offset = node_offset;
+            height_offset += data.heights.len() as u32;
+            node_offset += data.nodes.len() as u32;
+        }
+        material.terrains = terrains;
+        // the samples are the bulk of it, so they're only packed again when one of them changed
+        if std::mem::take(&mut *shapes_res.terrain_data_changed.lock().unwrap()) {
+            material.terrain_heights = terrain_data.iter().flat_map(|data| data.heights.iter().copied()).collect();
+            material.terrain_nodes = terrain_data.iter().flat_map(|data| data.nodes.iter().copied()).collect();
+        }
+        drop(terrain_data);
+        material.jitter = if temporal.accumulating(*mode) { frame_jitter(frame.0) } else { Vec2::ZERO };
+        material.mode = *mode as u32;
+        material.frame = frame.0;
+        material.stack_size = bvh_stack_size(*tree_res.depth.lock().unwrap());
+        material.cone_prepass = cone_prepass.enabled as u32;
+    }
+}
+
+// subpixel offset in pixels, the update_temporal uniform has to agree with the material on it
+fn frame_jitter(frame: u32) -> Vec2 {
+    vec2(halton(frame % 16 + 1, 2), halton(frame % 16 + 1, 3)) - 0.5
+}
+
+fn halton(mut index: u32, base: u32) -> f32 {
+    let mut f = 1.0;
+    let mut r = 0.0;
+    while index > 0 {
+        f /= base as f32;
+        r += f * (index % base) as f32;
+        index /= base;
+    }
+    r
+}
+
+
+
+type ShapeMut = AnyOf<(&'static mut SdSphere, &'static mut SdCube, &'static mut SdEllipse, &'static mut SdTorus, &'static mut SdCylinder, &'static mut SdCone)>;
+
+// the modifier fields every shape struct carries, borrowed from whichever one an entity has
+struct ShapeModifiers<'a>{
+    displacement: &'a mut SdDisplacement,
+    deform: &'a mut SdDeform,
+    repeat: &'a mut ShapeRepeat,
+}
+
+fn modify_shape(shapes_q: &mut Query<ShapeMut>, entity: Entity, f: impl FnOnce(ShapeModifiers)) {
+    let Ok(shape) = shapes_q.get_mut(entity) else {
+        return;
+    };
+    match shape {
+        (Some(mut sphere), ..) => {
+            let sphere = &mut *sphere;
+            f(ShapeModifiers{displacement: &mut sphere.displacement, deform: &mut sphere.deform, repeat: &mut sphere.repeat})
+        }
+        (_, Some(mut cube), ..) => {
+            let cube = &mut *cube;
+            f(ShapeModifiers{displacement: &mut cube.displacement, deform: &mut cube.deform, repeat: &mut cube.repeat})
+        }
+        (_, _, Some(mut ellipse), ..) => {
+            let ellipse = &mut *ellipse;
+            f(ShapeModifiers{displacement: &mut ellipse.displacement, deform: &mut ellipse.deform, repeat: &mut ellipse.repeat})
+        }
+        (_, _, _, Some(mut torus), ..) => {
+            let torus = &mut *torus;
+            f(ShapeModifiers{displacement: &mut torus.displacement, deform: &mut torus.deform, repeat: &mut torus.repeat})
+        }
+        (_, _, _, _, Some(mut cylinder), _) => {
+            let cylinder = &mut *cylinder;
+            f(ShapeModifiers{displacement: &mut cylinder.displacement, deform: &mut cylinder.deform, repeat: &mut cylinder.repeat})
+        }
+        (_, _, _, _, _, Some(mut cone)) => {
+            let cone = &mut *cone;
+            f(ShapeModifiers{displacement: &mut cone.displacement, deform: &mut cone.deform, repeat: &mut cone.repeat})
+        }
+        _ => {}
+    }
+}
+
+// the shader only sees the shape structs, so displacement is copied into them. that also marks the shape
+// changed, so push_shapes refits its leaf with the grown aabb
+fn apply_displacement(
+    displaced_q: Query<(Entity, &SdDisplacement), Changed<SdDisplacement>>,
+    mut removed: RemovedComponents<SdDisplacement>,
+    mut shapes_q: Query<ShapeMut>,
+){
+    let changes = displaced_q.iter().map(|(entity, displacement)| (entity, *displacement))
+        .chain(removed.read().map(|entity| (entity, SdDisplacement::default())));
+
+    for (entity, displacement) in changes {
+        modify_shape(&mut shapes_q, entity, |shape| *shape.displacement = displacement);
+    }
+}
+
+fn apply_deform(
+    deformed_q: Query<(Entity, &SdDeform), Changed<SdDeform>>,
+    mut removed: RemovedComponents<SdDeform>,
+    mut shapes_q: Query<ShapeMut>,
+){
+    let changes = deformed_q.iter().map(|(entity, deform)| (entity, *deform))
+        .chain(removed.read().map(|entity| (entity, SdDeform::default())));
+
+    for (entity, deform) in changes {
+        modify_shape(&mut shapes_q, entity, |shape| *shape.deform = deform);
+    }
+}
+
+fn apply_repeat(
+    repeated_q: Query<(Entity, &SdRepeat), Changed<SdRepeat>>,
+    mut removed: RemovedComponents<SdRepeat>,
+    mut shapes_q: Query<ShapeMut>,
+){
+    let changes = repeated_q.iter().map(|(entity, repeat)| (entity, ShapeRepeat::from(*repeat)))
+        .chain(removed.read().map(|entity| (entity, ShapeRepeat::default())));
+
+    for (entity, repeat) in changes {
+        modify_shape(&mut shapes_q, entity, |shape| *shape.repeat = repeat);
+    }
+}
+
+// bakes terrains that changed or are still waiting on their heightmap, marking them changed once it's done
+// so push_shapes picks up the new heights and bounds
+fn bake_terrain(
+    images: Res<Assets<Image>>,
+    mut terrains: Query<&mut SdTerrain>,
+){
+    for mut terrain in &mut terrains {
+        if !terrain.is_changed() && !terrain.pending {
+            continue;
+        }
+        if terrain.bypass_change_detection().bake(&images) {
+            terrain.set_changed();
+        }
+    }
+}
+
+// keeps the container's copy of every prototype current, the instances using one that changed get refit
+fn sync_prototypes(
+    container: Res<ShapeContainer>,
+    assets: Res<Assets<SdPrototype>>,
+    mut events: EventReader<AssetEvent<SdPrototype>>,
+    mut instances: Query<&mut SdInstance>,
+){
+    let mut changed = vec![];
+    for event in events.read() {
+        match *event {
+            AssetEvent::Added{id} | AssetEvent::Modified{id} => {
+                if let Some(prototype) = assets.get(id) {
+                    container.prototypes.lock().unwrap().insert(id, Blas::new(prototype));
+                    changed.push(id);
+                }
+            }
+            AssetEvent::Removed{id} | AssetEvent::Unused{id} => {
+                container.prototypes.lock().unwrap().remove(&id);
+                changed.push(id);
+            }
+            AssetEvent::LoadedWithDependencies{..} => {}
+        }
+    }
+    if changed.is_empty() {
+        return;
+    }
+    *container.prototype_parts.lock().unwrap() = PrototypeParts::new(&container.prototypes.lock().unwrap());
+    for mut instance in &mut instances {
+        if changed.contains(&instance.prototype.id()) {
+            instance.set_changed();
+        }
+    }
+}
+
+fn push_shapes(
+    container: ResMut<ShapeContainer>,
+    tree: ResMut<BvhTree>,
+    mut spheres: ParamSet<(
+        Query<(&mut SdSphere, &GlobalTransform), Added<SdSphere>>,
+        Query<(&mut SdSphere, &GlobalTransform), Or<(Changed<SdSphere>, Changed<GlobalTransform>)>>,
+    )>,
+    mut cubes: ParamSet<(
+        Query<(&mut SdCube, &GlobalTransform), Added<SdCube>>,   
+        Query<(&mut SdCube, &GlobalTransform), Or<(Changed<SdCube>, Changed<GlobalTransform>)>>,
+    )>,
+    mut ellipses: ParamSet<(
+        Query<(&mut SdEllipse, &GlobalTransform), Added<SdEllipse>>,   
+        Query<(&mut SdEllipse, &GlobalTransform), Or<(Changed<SdEllipse>, Changed<GlobalTransform>)>>,
+    )>,
+    mut toruses: ParamSet<(
+        Query<(&mut SdTorus, &GlobalTransform), Added<SdTorus>>,   
+        Query<(&mut SdTorus, &GlobalTransform), Or<(Changed<SdTorus>, Changed<GlobalTransform>)>>,
+    )>,
+    mut cylinders: ParamSet<(
+        Query<(&mut SdCylinder, &GlobalTransform), Added<SdCylinder>>,   
+        Query<(&mut SdCylinder, &GlobalTransform), Or<(Changed<SdCylinder>, Changed<GlobalTransform>)>>,
+    )>,
+    mut cones: ParamSet<(
+        Query<(&mut SdCone, &GlobalTransform), Added<SdCone>>,   
+        Query<(&mut SdCone, &GlobalTransform), Or<(Changed<SdCone>, Changed<GlobalTransform>)>>,
+    )>,
+    mut fog_volumes: ParamSet<(
+        Query<(Entity, &mut SdFogVolume, &GlobalTransform), Added<SdFogVolume>>,   
+        Query<(&mut SdFogVolume, &GlobalTransform), Or<(Changed<SdFogVolume>, Changed<GlobalTransform>)>>,
+    )>,
+    mut terrains: ParamSet<(
+        Query<(Entity, &mut SdTerrain, &GlobalTransform), Added<SdTerrain>>,   
+        Query<(Entity, &SdTerrain, &GlobalTransform), Or<(Changed<SdTerrain>, Changed<GlobalTransform>)>>,
+    )>,
+    mut instances: ParamSet<(
+        Query<(Entity, &mut SdInstance, &GlobalTransform), Added<SdInstance>>,   
+        Query<(Entity, &SdInstance, &GlobalTransform), Or<(Changed<SdInstance>, Changed<GlobalTransform>)>>,
+    )>,
+
+){
+
+
+    //let pushing = Instant::now();
+
+    spheres.p0().par_iter_mut().for_each(|(mut sphere, gt)| {
+        let transform = gt.compute_matrix();
+        sphere.transform_determinant = transform.determinant();
+        sphere.inverse_transform = transform.inverse();
 
         let mut spheres = container.spheres.lock().unwrap();
         sphere.index = spheres.len() as u32;
@@ -502,11 +2134,14 @@ fn push_shapes(
         let transform = gt.compute_matrix();
         sphere.transform_determinant = transform.determinant();
         sphere.inverse_transform = transform.inverse();
-        container.spheres.lock().unwrap()[sphere.index as usize] = *sphere;
+        let mut spheres = container.spheres.lock().unwrap();
+        sphere.parent_idx = spheres[sphere.index as usize].parent_idx; //the container's leaf index is the live one
+        spheres[sphere.index as usize] = *sphere;
+        drop(spheres);
         let shape_idx = uvec2(1, sphere.index);
         
 
-        refit_leaf(shape_idx, &container, Arc::clone(&tree.nodes), Arc::clone(&tree.root_index));
+        refit_leaf(shape_idx, &container, Arc::clone(&tree.nodes));
         sphere.parent_idx = container.spheres.lock().unwrap()[sphere.index as usize].parent_idx;
     });
 
@@ -534,10 +2169,13 @@ fn push_shapes(
         let transform = gt.compute_matrix();
         cube.transform_determinant = transform.determinant();
         cube.inverse_transform = transform.inverse();
-        container.cubes.lock().unwrap()[cube.index as usize] = *cube;
+        let mut cubes = container.cubes.lock().unwrap();
+        cube.parent_idx = cubes[cube.index as usize].parent_idx;
+        cubes[cube.index as usize] = *cube;
+        drop(cubes);
         let shape_idx = uvec2(2, cube.index);
 
-        refit_leaf(shape_idx, &container, Arc::clone(&tree.nodes), Arc::clone(&tree.root_index));
+        refit_leaf(shape_idx, &container, Arc::clone(&tree.nodes));
         cube.parent_idx = container.cubes.lock().unwrap()[cube.index as usize].parent_idx;
     });
 
@@ -562,10 +2200,13 @@ fn push_shapes(
         let transform = gt.compute_matrix();
         ellipse.transform_determinant = transform.determinant();
         ellipse.inverse_transform = transform.inverse();
-        container.ellipses.lock().unwrap()[ellipse.index as usize] = *ellipse;
+        let mut ellipses = container.ellipses.lock().unwrap();
+        ellipse.parent_idx = ellipses[ellipse.index as usize].parent_idx;
+        ellipses[ellipse.index as usize] = *ellipse;
+        drop(ellipses);
         let shape_idx = uvec2(3, ellipse.index);
 
-        refit_leaf(shape_idx, &container, Arc::clone(&tree.nodes), Arc::clone(&tree.root_index));
+        refit_leaf(shape_idx, &container, Arc::clone(&tree.nodes));
         ellipse.parent_idx = container.ellipses.lock().unwrap()[ellipse.index as usize].parent_idx;
     });
 
@@ -588,10 +2229,13 @@ fn push_shapes(
         let transform = gt.compute_matrix();
         torus.transform_determinant = transform.determinant();
         torus.inverse_transform = transform.inverse();
-        container.toruses.lock().unwrap()[torus.index as usize] = *torus;
+        let mut toruses = container.toruses.lock().unwrap();
+        torus.parent_idx = toruses[torus.index as usize].parent_idx;
+        toruses[torus.index as usize] = *torus;
+        drop(toruses);
         let shape_idx = uvec2(4, torus.index);
 
-        refit_leaf(shape_idx, &container, Arc::clone(&tree.nodes), Arc::clone(&tree.root_index));
+        refit_leaf(shape_idx, &container, Arc::clone(&tree.nodes));
         torus.parent_idx = container.toruses.lock().unwrap()[torus.index as usize].parent_idx;
     });
     cylinders.p0().par_iter_mut().for_each(|(mut cylinder, gt)| {
@@ -613,10 +2257,13 @@ fn push_shapes(
         let transform = gt.compute_matrix();
         cylinder.transform_determinant = transform.determinant();
         cylinder.inverse_transform = transform.inverse();
-        container.cylinders.lock().unwrap()[cylinder.index as usize] = *cylinder;
+        let mut cylinders = container.cylinders.lock().unwrap();
+        cylinder.parent_idx = cylinders[cylinder.index as usize].parent_idx;
+        cylinders[cylinder.index as usize] = *cylinder;
+        drop(cylinders);
         let shape_idx = uvec2(5, cylinder.index);
 
-        refit_leaf(shape_idx, &container, Arc::clone(&tree.nodes), Arc::clone(&tree.root_index));
+        refit_leaf(shape_idx, &container, Arc::clone(&tree.nodes));
         cylinder.parent_idx = container.cylinders.lock().unwrap()[cylinder.index as usize].parent_idx;
     });
 
@@ -632,83 +2279,595 @@ fn push_shapes(
 
         let shape_idx = uvec2(6, cone.index);
 
-        insert_leaf(shape_idx, &container, Arc::clone(&tree.nodes), Arc::clone(&tree.node_count), Arc::clone(&tree.root_index));
+        insert_leaf(shape_idx, &container, Arc::clone(&tree.nodes), Arc::clone(&tree.node_count), Arc::clone(&tree.root_index));
+
+        cone.parent_idx = container.cones.lock().unwrap()[cone.index as usize].parent_idx;
+    });
+    cones.p1().par_iter_mut().for_each(|(mut cone, gt)| { 
+        let transform = gt.compute_matrix();
+        cone.transform_determinant = transform.determinant();
+        cone.inverse_transform = transform.inverse();
+        let mut cones = container.cones.lock().unwrap();
+        cone.parent_idx = cones[cone.index as usize].parent_idx;
+        cones[cone.index as usize] = *cone;
+        drop(cones);
+        let shape_idx = uvec2(6, cone.index);
+
+        refit_leaf(shape_idx, &container, Arc::clone(&tree.nodes));
+        cone.parent_idx = container.cones.lock().unwrap()[cone.index as usize].parent_idx;
+    });
+    fog_volumes.p0().par_iter_mut().for_each(|(entity, mut fog_volume, gt)| {
+        let transform = gt.compute_matrix();
+        fog_volume.transform_determinant = transform.determinant();
+        fog_volume.inverse_transform = transform.inverse();
+        let mut fog_volumes = container.fog_volumes.lock().unwrap();
+        fog_volume.index = fog_volumes.len() as u32;
+        fog_volumes.push(*fog_volume);
+        container.fog_volume_entities.lock().unwrap().push(entity);
+        drop(fog_volumes);
+
+        let shape_idx = uvec2(7, fog_volume.index);
+
+        insert_leaf(shape_idx, &container, Arc::clone(&tree.nodes), Arc::clone(&tree.node_count), Arc::clone(&tree.root_index));
+
+        fog_volume.parent_idx = container.fog_volumes.lock().unwrap()[fog_volume.index as usize].parent_idx;
+    });
+    fog_volumes.p1().par_iter_mut().for_each(|(mut fog_volume, gt)| { 
+        let transform = gt.compute_matrix();
+        fog_volume.transform_determinant = transform.determinant();
+        fog_volume.inverse_transform = transform.inverse();
+        let mut fog_volumes = container.fog_volumes.lock().unwrap();
+        fog_volume.parent_idx = fog_volumes[fog_volume.index as usize].parent_idx;
+        fog_volumes[fog_volume.index as usize] = *fog_volume;
+        drop(fog_volumes);
+        let shape_idx = uvec2(7, fog_volume.index);
+
+        refit_leaf(shape_idx, &container, Arc::clone(&tree.nodes));
+        fog_volume.parent_idx = container.fog_volumes.lock().unwrap()[fog_volume.index as usize].parent_idx;
+    });
+
+    // the container keeps the gpu side of a terrain, its baked heights and chunk tree sit next to it in terrain_data
+    terrains.p0().par_iter_mut().for_each(|(entity, mut terrain, gt)| {
+        let mut terrains = container.terrains.lock().unwrap();
+        terrain.index = terrains.len() as u32;
+        terrains.push(terrain.shape(gt));
+        container.terrain_data.lock().unwrap().push(terrain.data(entity));
+        *container.terrain_data_changed.lock().unwrap() = true;
+        drop(terrains);
+
+        let shape_idx = uvec2(8, terrain.index);
+
+        insert_leaf(shape_idx, &container, Arc::clone(&tree.nodes), Arc::clone(&tree.node_count), Arc::clone(&tree.root_index));
+    });
+
+    terrains.p1().par_iter().for_each(|(entity, terrain, gt)| { 
+        let mut terrains = container.terrains.lock().unwrap();
+        let parent_idx = terrains[terrain.index as usize].parent_idx;
+        terrains[terrain.index as usize] = TerrainShape{parent_idx, ..terrain.shape(gt)};
+        let data = terrain.data(entity);
+        let mut terrain_data = container.terrain_data.lock().unwrap();
+        let old = &terrain_data[terrain.index as usize];
+        if !Arc::ptr_eq(&old.heights, &data.heights) || !Arc::ptr_eq(&old.nodes, &data.nodes) {
+            *container.terrain_data_changed.lock().unwrap() = true; //only moved otherwise
+        }
+        terrain_data[terrain.index as usize] = data;
+        drop(terrain_data);
+        drop(terrains);
+        let shape_idx = uvec2(8, terrain.index);
+
+        refit_leaf(shape_idx, &container, Arc::clone(&tree.nodes));
+    });
+
+    // instances only carry a transform and the prototype's id, the shape itself goes up once per prototype
+    instances.p0().par_iter_mut().for_each(|(entity, mut instance, gt)| {
+        let mut instances = container.instances.lock().unwrap();
+        instance.index = instances.len() as u32;
+        instances.push(ShapeInstance::new(entity, &instance, gt));
+        drop(instances);
+
+        let shape_idx = uvec2(9, instance.index);
+
+        insert_leaf(shape_idx, &container, Arc::clone(&tree.nodes), Arc::clone(&tree.node_count), Arc::clone(&tree.root_index));
+    });
+
+    instances.p1().par_iter().for_each(|(entity, instance, gt)| { 
+        let mut instances = container.instances.lock().unwrap();
+        let parent_idx = instances[instance.index as usize].parent_idx;
+        instances[instance.index as usize] = ShapeInstance{parent_idx, ..ShapeInstance::new(entity, instance, gt)};
+        drop(instances);
+        let shape_idx = uvec2(9, instance.index);
+
+        refit_leaf(shape_idx, &container, Arc::clone(&tree.nodes));
+    });
+
+}
+
+
+
+//structs
+
+#[derive(Debug, Clone, Copy, ShaderType)]
+struct Aabb{
+    min: Vec3,
+    max: Vec3,
+}
+
+impl Default for Aabb {
+    fn default() -> Self { 
+        Self{
+            min: Vec3::MAX, 
+            max: Vec3::MIN
+        } }
+}
+
+#[derive(Default, Debug, Clone, Copy)]
+struct Obb{
+    center: Vec3,
+    size: Vec3,
+    rotation: Quat,
+}
+
+#[derive(Default, Debug, Clone, Copy, ShaderType)]
+struct BvhNode{
+    o_p_idx: UVec2,
+    aabb: Aabb,
+    child1: UVec2,
+    child2: UVec2,
+}
+
+#[derive(Resource, Default, Debug, Clone)]
+struct BvhTree{
+    node_count: Arc<Mutex<u32>>,
+    root_index: Arc<Mutex<u32>>,
+    nodes: Arc<Mutex<Vec<BvhNode>>>,
+    depth: Arc<Mutex<u32>>, //levels of nodes from the root down to the deepest leaf
+}
+
+// incremental inserts can leave long chains, past this the tree gets rebuilt top down
+const MAX_BVH_DEPTH: u32 = 32;
+
+// a traversal holds at most one pending sibling per level plus the two children it just pushed,
+// rounded up so the pipelines don't get respecialized every time a shape goes in
+fn bvh_stack_size(depth: u32) -> u32 {
+    (depth + 1).next_multiple_of(8)
+}
+
+#[derive(Resource, Default, Debug, Clone)]
+struct ShapeContainer{
+    spheres: Arc<Mutex<Vec<SdSphere>>>,
+    cubes: Arc<Mutex<Vec<SdCube>>>,
+    ellipses: Arc<Mutex<Vec<SdEllipse>>>,
+    toruses: Arc<Mutex<Vec<SdTorus>>>,
+    cylinders: Arc<Mutex<Vec<SdCylinder>>>,
+    cones: Arc<Mutex<Vec<SdCone>>>,
+    fog_volumes: Arc<Mutex<Vec<SdFogVolume>>>,
+    fog_volume_entities: Arc<Mutex<Vec<Entity>>>, //same order as fog_volumes, so the remove hook can fix a swapped index
+    terrains: Arc<Mutex<Vec<TerrainShape>>>,
+    terrain_data: Arc<Mutex<Vec<TerrainData>>>, //same order as terrains
+    terrain_data_changed: Arc<Mutex<bool>>, //a terrain was added, removed or baked again, set_mat_values repacks the samples
+    instances: Arc<Mutex<Vec<ShapeInstance>>>,
+    prototypes: Arc<Mutex<HashMap<AssetId<SdPrototype>, Blas>>>, //loaded definitions, kept in step by sync_prototypes
+    prototype_parts: Arc<Mutex<PrototypeParts>>, //the same definitions packed for the gpu
+}
+
+
+#[derive(Resource, Debug, Clone, Copy, Reflect)]
+#[reflect(Resource)]
+pub struct RaymarchSettings{
+    pub lower_colour: Vec3,
+    pub middle_colour: Vec3,
+    pub upper_colour: Vec3,
+    pub max_distance: f32,
+    pub skybox_powers: Vec2,
+    pub shadow_power: f32,
+    pub bounces: u32, //path traced mode only
+    pub max_steps: u32,
+    pub hit_epsilon: f32, //scaled by t
+    pub shadow_steps: u32,
+    pub normal_epsilon: f32,
+    pub shadow_bias: f32,
+    pub normal_mode: NormalMode,
+    pub normal_epsilon_scale: f32, //added to normal_epsilon per unit of t, so far away surfaces don't facet
+    pub sky_mode: SkyMode,
+    pub sun_intensity: f32, //atmosphere only
+    pub mie_g: f32, //atmosphere only, how forward the haze around the sun scatters
+    pub fog_density: f32, //exponential fog over the hit distance, 0 turns it off
+    pub fog_height_density: f32, //fog thickness at y = 0, thinning out above it
+    pub fog_height_falloff: f32,
+    pub fog_colour: Vec3,
+    pub fog_sky: FogTint,
+    pub fog_volume_steps: u32, //fixed samples through each SdFogVolume the ray crosses
+    pub tonemapping: RaymarchTonemapping, //applied by the RayCamera's Tonemapping after meshes are composited
+    pub exposure: f32, //stops, also the compensation on top of auto exposure
+    pub auto_exposure: bool, //meters the composited frame and adapts exposure over time
+}
+
+// the marching parameters start out as the Medium preset, apply_quality swaps them for the chosen one
+impl Default for RaymarchSettings {
+    fn default() -> Self {
+        let mut settings = Self{
+            lower_colour: vec3(0.1, 0.1, 0.2),
+            middle_colour: vec3(0.1, 0.1, 0.4),
+            upper_colour: vec3(0.4, 0.5, 0.75),
+            max_distance: 1000.0,
+            skybox_powers: vec2(2.0, 0.6),
+            shadow_power: 0.005,
+            bounces: 3,
+            max_steps: 0,
+            hit_epsilon: 0.0,
+            shadow_steps: 0,
+            normal_epsilon: 0.0,
+            shadow_bias: 0.0,
+            normal_mode: NormalMode::Analytic,
+            normal_epsilon_scale: 0.0005,
+            sky_mode: SkyMode::Atmosphere,
+            sun_intensity: 20.0,
+            mie_g: 0.76,
+            fog_density: 0.002,
+            fog_height_density: 0.02,
+            fog_height_falloff: 0.15,
+            fog_colour: vec3(0.5, 0.6, 0.7),
+            fog_sky: FogTint::Sky,
+            fog_volume_steps: 0,
+            tonemapping: RaymarchTonemapping::AgX,
+            exposure: 0.0,
+            auto_exposure: false,
+        };
+        RaymarchQuality::Medium.apply(&mut settings);
+        settings
+    }
+}
+
+// what the shader sees of RaymarchSettings, the camera side values stay on the cpu
+#[derive(Default, Debug, Clone, Copy, ShaderType)]
+struct GpuRaymarchSettings{
+    lower_colour: Vec3,
+    middle_colour: Vec3,
+    upper_colour: Vec3,
+    max_distance: f32,
+    skybox_powers: Vec2,
+    shadow_power: f32,
+    bounces: u32,
+    max_steps: u32,
+    hit_epsilon: f32,
+    shadow_steps: u32,
+    normal_epsilon: f32,
+    shadow_bias: f32,
+    normal_mode: u32,
+    normal_epsilon_scale: f32,
+    sky_mode: u32,
+    sun_intensity: f32,
+    mie_g: f32,
+    fog_density: f32,
+    fog_height_density: f32,
+    fog_height_falloff: f32,
+    fog_colour: Vec3,
+    fog_sky: u32,
+    fog_volume_steps: u32,
+}
+
+impl From<RaymarchSettings> for GpuRaymarchSettings{
+    fn from(settings: RaymarchSettings) -> Self {
+        GpuRaymarchSettings{
+            lower_colour: settings.lower_colour,
+            middle_colour: settings.middle_colour,
+            upper_colour: settings.upper_colour,
+            max_distance: settings.max_distance,
+            skybox_powers: settings.skybox_powers,
+            shadow_power: settings.shadow_power,
+            bounces: settings.bounces,
+            max_steps: settings.max_steps,
+            hit_epsilon: settings.hit_epsilon,
+            shadow_steps: settings.shadow_steps,
+            normal_epsilon: settings.normal_epsilon,
+            shadow_bias: settings.shadow_bias,
+            normal_mode: settings.normal_mode as u32,
+            normal_epsilon_scale: settings.normal_epsilon_scale,
+            sky_mode: settings.sky_mode as u32,
+            sun_intensity: settings.sun_intensity,
+            mie_g: settings.mie_g,
+            fog_density: settings.fog_density,
+            fog_height_density: settings.fog_height_density,
+            fog_height_falloff: settings.fog_height_falloff,
+            fog_colour: settings.fog_colour,
+            fog_sky: settings.fog_sky as u32,
+            fog_volume_steps: settings.fog_volume_steps,
+        }
+    }
+}
+
+// the values match the normal_mode switch in the shader
+#[derive(Default, Debug, Clone, Copy, PartialEq, Eq, Reflect)]
+#[repr(u32)]
+pub enum NormalMode{
+    #[default]
+    ForwardDifferences = 0,
+    Tetrahedral = 1,
+    Analytic = 2, //where the shape has one, tetrahedral otherwise
+}
+
+#[derive(Default, Debug, Clone, Copy, PartialEq, Eq, Reflect)]
+#[repr(u32)]
+pub enum SkyMode{
+    #[default]
+    Gradient = 0, //the three colours in RaymarchSettings
+    Atmosphere = 1, //rayleigh + mie scattering lit by the first directional light
+}
+
+#[derive(Default, Debug, Clone, Copy, PartialEq, Eq, Reflect)]
+#[repr(u32)]
+pub enum FogTint{
+    #[default]
+    Colour = 0, //fog_colour
+    Sky = 1, //the sky colour in the ray's direction
+}
+
+#[derive(Default, Debug, Clone, Copy, PartialEq, Eq, Reflect)]
+pub enum RaymarchTonemapping{
+    #[default]
+    None,
+    Reinhard,
+    Aces,
+    AgX,
+}
+
+impl From<RaymarchTonemapping> for Tonemapping{
+    fn from(tonemapping: RaymarchTonemapping) -> Self {
+        match tonemapping {
+            RaymarchTonemapping::None => Tonemapping::None,
+            RaymarchTonemapping::Reinhard => Tonemapping::Reinhard,
+            RaymarchTonemapping::Aces => Tonemapping::AcesFitted,
+            RaymarchTonemapping::AgX => Tonemapping::AgX,
+        }
+    }
+}
+
+// named sets of the marching parameters above, switching preset overwrites them
+#[derive(Resource, Default, Debug, Clone, Copy, PartialEq, Eq, Reflect)]
+#[reflect(Resource)]
+pub enum RaymarchQuality{
+    Low,
+    Medium,
+    #[default]
+    High,
+    Ultra,
+}
+
+impl RaymarchQuality{
+    pub fn apply(self, settings: &mut RaymarchSettings){
+        let (max_steps, hit_epsilon, shadow_steps, normal_epsilon, shadow_bias, fog_volume_steps) = match self {
+            RaymarchQuality::Low => (32, 0.004, 4, 0.002, 0.3, 8),
+            RaymarchQuality::Medium => (48, 0.003, 6, 0.0015, 0.25, 16),
+            RaymarchQuality::High => (64, 0.002, 8, 0.001, 0.2, 24),
+            RaymarchQuality::Ultra => (128, 0.001, 16, 0.0005, 0.1, 48),
+        };
+        settings.max_steps = max_steps;
+        settings.hit_epsilon = hit_epsilon;
+        settings.shadow_steps = shadow_steps;
+        settings.normal_epsilon = normal_epsilon;
+        settings.shadow_bias = shadow_bias;
+        settings.fog_volume_steps = fog_volume_steps;
+    }
+}
 
-        cone.parent_idx = container.cones.lock().unwrap()[cone.index as usize].parent_idx;
-    });
-    cones.p1().par_iter_mut().for_each(|(mut cone, gt)| { 
-        let transform = gt.compute_matrix();
-        cone.transform_determinant = transform.determinant();
-        cone.inverse_transform = transform.inverse();
-        container.cones.lock().unwrap()[cone.index as usize] = *cone;
-        let shape_idx = uvec2(6, cone.index);
+// only on change, so tweaking the individual values in the inspector sticks until the next preset switch
+fn apply_quality(
+    quality: Res<RaymarchQuality>,
+    mut settings: ResMut<RaymarchSettings>,
+){
+    if quality.is_changed() {
+        quality.apply(&mut settings);
+    }
+}
 
-        refit_leaf(shape_idx, &container, Arc::clone(&tree.nodes), Arc::clone(&tree.root_index));
-        cone.parent_idx = container.cones.lock().unwrap()[cone.index as usize].parent_idx;
-    });
+// the raymarched image and any meshes share the RayCamera's hdr target, so tonemapping and exposure happen once
+// on the composite through bevy's own tonemapping pass. only pushed when those three fields change, so the
+// camera's own components can still be tweaked directly in between
+fn apply_tonemapping(
+    mut commands: Commands,
+    settings: Res<RaymarchSettings>,
+    mut applied: Local<Option<(RaymarchTonemapping, f32, bool)>>,
+    mut camera_q: Query<(Entity, &mut Tonemapping, &mut ColorGrading, Has<AutoExposureSettings>), With<RayCamera>>,
+){
+    let wanted = Some((settings.tonemapping, settings.exposure, settings.auto_exposure));
+    if *applied == wanted || camera_q.is_empty() {
+        return;
+    }
+    *applied = wanted;
+    for (entity, mut tonemapping, mut grading, has_auto_exposure) in &mut camera_q {
+        let wanted = Tonemapping::from(settings.tonemapping);
+        if *tonemapping != wanted {
+            *tonemapping = wanted;
+        }
+        if grading.global.exposure != settings.exposure {
+            grading.global.exposure = settings.exposure;
+        }
+        match (settings.auto_exposure, has_auto_exposure) {
+            (true, false) => { commands.entity(entity).insert(AutoExposureSettings::default()); }
+            (false, true) => { commands.entity(entity).remove::<AutoExposureSettings>(); }
+            _ => {}
+        }
+    }
+}
 
+// textures for shapes, a shape's texture field picks a layer (plus one). every albedo has to share the first one's
+// size and be rgba8, normals are tangent space and default to flat. load normal maps with is_srgb off
+#[derive(Resource, Default)]
+pub struct RaymarchTextures{
+    pub layers: Vec<RaymarchTexture>,
+    built: bool,
 }
 
+pub struct RaymarchTexture{
+    pub albedo: Handle<Image>,
+    pub normal: Option<Handle<Image>>,
+}
 
+// stacks the layers into the two array textures once every image has loaded, again whenever the layers change
+fn build_texture_arrays(
+    mut textures: ResMut<RaymarchTextures>,
+    mut images: ResMut<Assets<Image>>,
+    mut materials: ResMut<Assets<RaymarchMaterial>>,
+    march_handle_q: Query<&Handle<RaymarchMaterial>, With<RayImage>>,
+){
+    if textures.layers.is_empty() || (textures.built && !textures.is_changed()) {
+        return;
+    }
 
-//structs
+    let handles = textures.layers.iter().map(|layer| (layer.albedo.clone(), layer.normal.clone())).collect::<Vec<_>>();
+    let Some(first) = images.get(&handles[0].0) else {
+        return;
+    };
+    let size = first.texture_descriptor.size;
+    let layer_bytes = (size.width * size.height * 4) as usize;
+
+    let mut albedo_data = Vec::with_capacity(layer_bytes * handles.len());
+    let mut normal_data = Vec::with_capacity(layer_bytes * handles.len());
+    for (albedo, normal) in &handles {
+        let Some(albedo) = images.get(albedo) else {
+            return;
+        };
+        let normal = match normal {
+            Some(handle) => match images.get(handle) {
+                Some(image) => Some(image),
+                None => return,
+            },
+            None => None,
+        };
+        for image in std::iter::once(albedo).chain(normal) {
+            if image.texture_descriptor.size != size || image.data.len() != layer_bytes {
+                warn!("raymarch textures need to be rgba8 and {}x{}, skipping them", size.width, size.height);
+                textures.bypass_change_detection().built = true;
+                return;
+            }
+        }
+        albedo_data.extend_from_slice(&albedo.data);
+        match normal {
+            Some(image) => normal_data.extend_from_slice(&image.data),
+            None => normal_data.extend([128, 128, 255, 255].repeat(layer_bytes / 4)),
+        }
+    }
 
-#[derive(Debug, Clone, Copy, ShaderType)]
-struct Aabb{
-    min: Vec3,
-    max: Vec3,
+    let layers = handles.len() as u32;
+    let albedo_array = images.add(texture_array(size, layers, albedo_data, TextureFormat::Rgba8UnormSrgb));
+    let normal_array = images.add(texture_array(size, layers, normal_data, TextureFormat::Rgba8Unorm));
+    for handle in &march_handle_q {
+        if let Some(material) = materials.get_mut(handle) {
+            material.albedo_textures = Some(albedo_array.clone());
+            material.normal_textures = Some(normal_array.clone());
+        }
+    }
+    textures.bypass_change_detection().built = true;
 }
 
-impl Default for Aabb {
-    fn default() -> Self { 
-        Self{
-            min: Vec3::MAX, 
-            max: Vec3::MIN
-        } }
+fn texture_array(size: Extent3d, layers: u32, data: Vec<u8>, format: TextureFormat) -> Image {
+    let mut image = Image::new(
+        Extent3d{depth_or_array_layers: layers, ..size},
+        TextureDimension::D2,
+        data,
+        format,
+        RenderAssetUsages::RENDER_WORLD,
+    );
+    // a single layer would otherwise get a plain 2d view
+    image.texture_view_descriptor = Some(TextureViewDescriptor{
+        dimension: Some(TextureViewDimension::D2Array),
+        ..Default::default()
+    });
+    image.sampler = ImageSampler::Descriptor(ImageSamplerDescriptor{
+        address_mode_u: ImageAddressMode::Repeat,
+        address_mode_v: ImageAddressMode::Repeat,
+        mag_filter: ImageFilterMode::Linear,
+        min_filter: ImageFilterMode::Linear,
+        ..Default::default()
+    });
+    image
 }
 
-#[derive(Default, Debug, Clone, Copy)]
-struct Obb{
-    center: Vec3,
-    size: Vec3,
-    rotation: Quat,
-}
+// square tiles with bevelled grout for the demo ground, so there's something to look at without shipping images
+fn tile_textures(size: u32) -> (Image, Image) {
+    let tile = size as f32 / 2.0;
+    let height = |x: f32, y: f32| {
+        let edge = (x.rem_euclid(tile)).min(tile - x.rem_euclid(tile)).min(y.rem_euclid(tile)).min(tile - y.rem_euclid(tile));
+        (edge / 3.0).min(1.0)
+    };
 
-#[derive(Default, Debug, Clone, Copy, ShaderType)]
-struct BvhNode{
-    o_p_idx: UVec2,
-    aabb: Aabb,
-    child1: UVec2,
-    child2: UVec2,
+    let mut albedo = Vec::with_capacity((size * size * 4) as usize);
+    let mut normal = Vec::with_capacity((size * size * 4) as usize);
+    for y in 0..size {
+        for x in 0..size {
+            let (fx, fy) = (x as f32 + 0.5, y as f32 + 0.5);
+            let h = height(fx, fy);
+            let shade = (90.0 + 150.0 * h) as u8;
+            albedo.extend([shade, shade, shade, 255]);
+
+            let n = vec3(height(fx - 1.0, fy) - height(fx + 1.0, fy), height(fx, fy - 1.0) - height(fx, fy + 1.0), 1.0).normalize();
+            let n = (n * 0.5 + 0.5) * 255.0;
+            normal.extend([n.x as u8, n.y as u8, n.z as u8, 255]);
+        }
+    }
+
+    let extent = Extent3d{width: size, height: size, depth_or_array_layers: 1};
+    (
+        Image::new(extent, TextureDimension::D2, albedo, TextureFormat::Rgba8UnormSrgb, RenderAssetUsages::default()),
+        Image::new(extent, TextureDimension::D2, normal, TextureFormat::Rgba8Unorm, RenderAssetUsages::default()),
+    )
 }
 
-#[derive(Resource, Default, Debug, Clone)]
-struct BvhTree{
-    node_count: Arc<Mutex<u32>>,
-    root_index: Arc<Mutex<u32>>,
-    nodes: Arc<Mutex<Vec<BvhNode>>>
+// drives the first directional light around the sky, the atmosphere follows it since it's lit by that light
+#[derive(Resource, Debug, Clone, Copy, Reflect)]
+#[reflect(Resource)]
+pub struct TimeOfDay{
+    pub enabled: bool,
+    pub hour: f32, //0..24, sunrise at 6 in the east (+x), sunset at 18
+    pub speed: f32, //hours per second
+    pub latitude: f32, //degrees, tilts the sun's path towards +z
 }
 
-#[derive(Resource, Default, Debug, Clone)]
-struct ShapeContainer{
-    spheres: Arc<Mutex<Vec<SdSphere>>>,
-    cubes: Arc<Mutex<Vec<SdCube>>>,
-    ellipses: Arc<Mutex<Vec<SdEllipse>>>,
-    toruses: Arc<Mutex<Vec<SdTorus>>>,
-    cylinders: Arc<Mutex<Vec<SdCylinder>>>,
-    cones: Arc<Mutex<Vec<SdCone>>>,
+fn time_of_day(
+    time: Res<Time>,
+    mut time_of_day: ResMut<TimeOfDay>,
+    mut light_q: Query<(&mut SdDirectionalLight, &mut Transform)>,
+){
+    if !time_of_day.enabled {
+        return;
+    }
+    if time_of_day.speed != 0.0 {
+        time_of_day.hour = (time_of_day.hour + time_of_day.speed * time.delta_seconds()).rem_euclid(24.0);
+    }
+
+    let Some((mut light, mut transform)) = light_q.iter_mut().next() else {
+        return;
+    };
+
+    let angle = (time_of_day.hour - 6.0) / 12.0 * std::f32::consts::PI;
+    let tilt = time_of_day.latitude.to_radians();
+    let sun = vec3(angle.cos(), angle.sin() * tilt.cos(), angle.sin() * tilt.sin()).normalize();
+
+    // the shader lights along the transform's up
+    transform.rotation = Quat::from_rotation_arc(Vec3::Y, sun);
+    light.colour = sun_transmittance(sun.y.asin().to_degrees());
 }
 
+// what's left of white sunlight after crossing the atmosphere at this elevation, same coefficients as the shader
+fn sun_transmittance(elevation: f32) -> Vec3 {
+    let rayleigh = vec3(5.5e-6, 13.0e-6, 22.4e-6) * 8000.0;
+    let mie = Vec3::splat(21e-6 * 1.1 * 1200.0);
+
+    // kasten and young's air mass, it only holds down to a few degrees under the horizon
+    let clamped = elevation.max(-3.5);
+    let air_mass = 1.0 / (clamped.to_radians().sin() + 0.50572 * (clamped + 6.07995).powf(-1.6364));
+    let fade = ((elevation + 6.0) / 6.0).clamp(0.0, 1.0);
+
+    (-(rayleigh + mie) * air_mass).exp() * fade
+}
 
-#[derive(Resource, Default, Debug, Clone, Copy, ShaderType, Reflect)]
+// Direct is the usual shading, PathTraced converges to a reference image while the camera holds still
+#[derive(Resource, Default, Debug, Clone, Copy, PartialEq, Eq, Reflect)]
 #[reflect(Resource)]
-pub struct RaymarchSettings{
-    pub lower_colour: Vec3,
-    pub middle_colour: Vec3,
-    pub upper_colour: Vec3,
-    pub max_distance: f32,
-    pub skybox_powers: Vec2,
-    pub shadow_power: f32,
+pub enum RaymarchMode{
+    #[default]
+    Direct,
+    PathTraced,
 }
 
 
@@ -726,6 +2885,19 @@ pub struct SdDirectionalLight{
     
 }
 
+// put next to a shape with some emissive to have it light its surroundings in direct mode,
+// the path tracer already picks emitters up by hitting them
+#[derive(Component, Default, Debug, Clone, Copy, Reflect)]
+pub struct SdEmissiveLight;
+
+// an emissive shape as the shader samples it, a sphere around the shape's centre
+#[derive(ShaderType, Default, Debug, Clone, Copy)]
+struct EmissiveLight{
+    position: Vec3,
+    radius: f32,
+    colour: Vec3, //already scaled by the emissive strength
+}
+
 #[derive(Component, ShaderType, Default, Debug, Clone, Copy, Reflect)]
 pub struct SdPositionalLight{
     pub strength: f32,
@@ -735,12 +2907,51 @@ pub struct SdPositionalLight{
     
 }
 
+// shines down the transform's forward, like bevy's SpotLight. full strength inside inner_angle,
+// fading out to nothing at outer_angle (both degrees from the centre) and at range
+#[derive(Component, ShaderType, Default, Debug, Clone, Copy, Reflect)]
+pub struct SdSpotLight{
+    pub strength: f32,
+    pub colour: Vec3,
+    pub inner_angle: f32,
+    pub outer_angle: f32,
+    pub range: f32,
+    translation: Vec3,
+    direction: Vec3,
+}
+
+// a light with a real size, so its shadows soften with distance from the occluder instead of by shadow_power.
+// shape 0 is a one sided rectangle of size.x by size.y facing the transform's forward, 1 is a sphere of radius size.x
+#[derive(Component, ShaderType, Default, Debug, Clone, Copy, Reflect)]
+pub struct SdAreaLight{
+    pub strength: f32,
+    pub colour: Vec3,
+    pub shape: u32,
+    pub size: Vec2,
+    translation: Vec3,
+    right: Vec3,
+    up: Vec3,
+    normal: Vec3,
+}
+
 
 
 #[derive(Component, ShaderType, Default, Debug, Clone, Copy, Reflect)]
 pub struct SdSphere{
     index: u32, //to the shape container for easy removal
     colour: Vec3,
+    pub emissive: f32,
+    pub texture: u32, //one more than the layer in RaymarchTextures, 0 for flat colour
+    pub texture_scale: f32, //repeats per unit in local space, 0 is treated as 1
+    pub pattern: u32, //0 none, 1 checker, 2 stripes, 3 value noise, 4 perlin noise, 5 cellular
+    pub pattern_scale: f32, //cells per unit in local space, 0 is treated as 1
+    pub pattern_colour: Vec3, //the pattern blends colour into this
+    pub rounding: f32, //radius added around the surface, rounds the edges off
+    pub onion: f32, //shell thickness, 0 for a solid shape
+    pub onion_layers: u32, //each layer past the first splits the shells again at half the thickness
+    displacement: SdDisplacement, //copied from an SdDisplacement on the same entity by apply_displacement
+    deform: SdDeform, //same for SdDeform, by apply_deform
+    repeat: ShapeRepeat, //and SdRepeat, by apply_repeat
     parent_idx: UVec2,
     pub radius: f32,
     transform_determinant: f32,
@@ -751,6 +2962,18 @@ pub struct SdSphere{
 pub struct SdCube{
     index: u32,
     colour: Vec3,
+    pub emissive: f32,
+    pub texture: u32, //one more than the layer in RaymarchTextures, 0 for flat colour
+    pub texture_scale: f32, //repeats per unit in local space, 0 is treated as 1
+    pub pattern: u32, //0 none, 1 checker, 2 stripes, 3 value noise, 4 perlin noise, 5 cellular
+    pub pattern_scale: f32, //cells per unit in local space, 0 is treated as 1
+    pub pattern_colour: Vec3, //the pattern blends colour into this
+    pub rounding: f32, //radius added around the surface, rounds the edges off
+    pub onion: f32, //shell thickness, 0 for a solid shape
+    pub onion_layers: u32, //each layer past the first splits the shells again at half the thickness
+    displacement: SdDisplacement, //copied from an SdDisplacement on the same entity by apply_displacement
+    deform: SdDeform, //same for SdDeform, by apply_deform
+    repeat: ShapeRepeat, //and SdRepeat, by apply_repeat
     parent_idx: UVec2,
     pub size: Vec3,
     transform_determinant: f32,
@@ -761,6 +2984,18 @@ pub struct SdCube{
 pub struct SdEllipse{
     index: u32,
     colour: Vec3,
+    pub emissive: f32,
+    pub texture: u32, //one more than the layer in RaymarchTextures, 0 for flat colour
+    pub texture_scale: f32, //repeats per unit in local space, 0 is treated as 1
+    pub pattern: u32, //0 none, 1 checker, 2 stripes, 3 value noise, 4 perlin noise, 5 cellular
+    pub pattern_scale: f32, //cells per unit in local space, 0 is treated as 1
+    pub pattern_colour: Vec3, //the pattern blends colour into this
+    pub rounding: f32, //radius added around the surface, rounds the edges off
+    pub onion: f32, //shell thickness, 0 for a solid shape
+    pub onion_layers: u32, //each layer past the first splits the shells again at half the thickness
+    displacement: SdDisplacement, //copied from an SdDisplacement on the same entity by apply_displacement
+    deform: SdDeform, //same for SdDeform, by apply_deform
+    repeat: ShapeRepeat, //and SdRepeat, by apply_repeat
     parent_idx: UVec2,
     pub radii: Vec3,
     transform_determinant: f32,
@@ -771,34 +3006,581 @@ pub struct SdEllipse{
 pub struct SdTorus{
     index: u32,
     colour: Vec3,
+    pub emissive: f32,
+    pub texture: u32, //one more than the layer in RaymarchTextures, 0 for flat colour
+    pub texture_scale: f32, //repeats per unit in local space, 0 is treated as 1
+    pub pattern: u32, //0 none, 1 checker, 2 stripes, 3 value noise, 4 perlin noise, 5 cellular
+    pub pattern_scale: f32, //cells per unit in local space, 0 is treated as 1
+    pub pattern_colour: Vec3, //the pattern blends colour into this
+    pub rounding: f32, //radius added around the surface, rounds the edges off
+    pub onion: f32, //shell thickness, 0 for a solid shape
+    pub onion_layers: u32, //each layer past the first splits the shells again at half the thickness
+    displacement: SdDisplacement, //copied from an SdDisplacement on the same entity by apply_displacement
+    deform: SdDeform, //same for SdDeform, by apply_deform
+    repeat: ShapeRepeat, //and SdRepeat, by apply_repeat
     parent_idx: UVec2,
     pub radii: Vec2,
     transform_determinant: f32,
     inverse_transform: Mat4,
 }
 
-#[derive(Component, ShaderType, Default, Debug, Clone, Copy, Reflect)]
-pub struct SdCylinder{
+#[derive(Component, ShaderType, Default, Debug, Clone, Copy, Reflect)]
+pub struct SdCylinder{
+    index: u32,
+    colour: Vec3,
+    pub emissive: f32,
+    pub texture: u32, //one more than the layer in RaymarchTextures, 0 for flat colour
+    pub texture_scale: f32, //repeats per unit in local space, 0 is treated as 1
+    pub pattern: u32, //0 none, 1 checker, 2 stripes, 3 value noise, 4 perlin noise, 5 cellular
+    pub pattern_scale: f32, //cells per unit in local space, 0 is treated as 1
+    pub pattern_colour: Vec3, //the pattern blends colour into this
+    pub rounding: f32, //radius added around the surface, rounds the edges off
+    pub onion: f32, //shell thickness, 0 for a solid shape
+    pub onion_layers: u32, //each layer past the first splits the shells again at half the thickness
+    displacement: SdDisplacement, //copied from an SdDisplacement on the same entity by apply_displacement
+    deform: SdDeform, //same for SdDeform, by apply_deform
+    repeat: ShapeRepeat, //and SdRepeat, by apply_repeat
+    parent_idx: UVec2,
+    pub height: f32,
+    pub radius: f32,
+    transform_determinant: f32,
+    inverse_transform: Mat4,
+}
+
+#[derive(Component, ShaderType, Default, Debug, Clone, Copy, Reflect)]
+pub struct SdCone{
+    index: u32,
+    colour: Vec3,
+    pub emissive: f32,
+    pub texture: u32, //one more than the layer in RaymarchTextures, 0 for flat colour
+    pub texture_scale: f32, //repeats per unit in local space, 0 is treated as 1
+    pub pattern: u32, //0 none, 1 checker, 2 stripes, 3 value noise, 4 perlin noise, 5 cellular
+    pub pattern_scale: f32, //cells per unit in local space, 0 is treated as 1
+    pub pattern_colour: Vec3, //the pattern blends colour into this
+    pub rounding: f32, //radius added around the surface, rounds the edges off
+    pub onion: f32, //shell thickness, 0 for a solid shape
+    pub onion_layers: u32, //each layer past the first splits the shells again at half the thickness
+    displacement: SdDisplacement, //copied from an SdDisplacement on the same entity by apply_displacement
+    deform: SdDeform, //same for SdDeform, by apply_deform
+    repeat: ShapeRepeat, //and SdRepeat, by apply_repeat
+    parent_idx: UVec2,
+    pub height: f32,
+    pub sincos: Vec2,
+    transform_determinant: f32,
+    inverse_transform: Mat4,
+}
+
+// perturbs the surface of the shape on the same entity with perlin noise or sine waves in its local space.
+// the shape's aabb grows by the amplitude and its distance is scaled down by the offset's steepest slope
+#[derive(Component, ShaderType, Default, Debug, Clone, Copy, Reflect)]
+pub struct SdDisplacement{
+    pub kind: u32, //0 perlin noise, 1 sine waves
+    pub amplitude: f32,
+    pub frequency: f32, //0 is treated as 1
+}
+
+// warps the space the shape on the same entity is evaluated in, applied in field order. twist and bend are
+// radians per unit, taper widens xz by that fraction per unit of y and shear slides xz per unit of y
+#[derive(Component, ShaderType, Default, Debug, Clone, Copy, Reflect)]
+pub struct SdDeform{
+    pub twist: f32,
+    pub bend: f32,
+    pub taper: f32,
+    pub shear: Vec2,
+}
+
+// tiles the shape on the same entity in a grid centred on it, along its local axes. spacing 0 leaves an axis
+// alone, count None repeats forever. infinite copies can't be bounded, so they skip the tree and every ray marches them
+#[derive(Component, Default, Debug, Clone, Copy, Reflect)]
+pub struct SdRepeat{
+    pub spacing: Vec3,
+    pub count: Option<UVec3>,
+}
+
+#[derive(ShaderType, Default, Debug, Clone, Copy, Reflect)]
+struct ShapeRepeat{
+    spacing: Vec3,
+    mode: u32, //0 single, 1 count copies per axis, 2 infinite
+    count: UVec3,
+}
+
+impl From<SdRepeat> for ShapeRepeat {
+    fn from(repeat: SdRepeat) -> Self {
+        match repeat.count {
+            Some(count) => Self { spacing: repeat.spacing, mode: 1, count },
+            None => Self { spacing: repeat.spacing, mode: 2, count: UVec3::ZERO },
+        }
+    }
+}
+
+// not a surface, rays march through it gathering density and light, bounded by a box or a sphere
+#[derive(Component, ShaderType, Default, Debug, Clone, Copy, Reflect)]
+pub struct SdFogVolume{
+    index: u32,
+    pub colour: Vec3, //tints the scattered light
+    pub density: f32,
+    parent_idx: UVec2,
+    pub size: Vec3, //half extents for a box, x is the radius for a sphere
+    pub shape: u32, //0 box, 1 sphere
+    pub anisotropy: f32, //henyey greenstein g, above 0 the shafts are brightest looking towards the light
+    transform_determinant: f32,
+    inverse_transform: Mat4,
+}
+
+
+// a heightfield over size.x by size.y half extents in its local xz plane, rising to height where the samples are 1.
+// the samples come from the red channel of heightmap, or fractal value noise while that's None. the footprint is split
+// into chunks with their own small tree, so rays only march the chunks they pass through
+#[derive(Component, Debug, Clone, Reflect)]
+pub struct SdTerrain{
+    pub colour: Vec3,
+    pub size: Vec2,
+    pub height: f32,
+    pub heightmap: Option<Handle<Image>>,
+    pub resolution: UVec2, //samples baked from the noise, a heightmap uses its own size
+    pub frequency: f32, //noise features per unit
+    pub octaves: u32,
+    pub seed: u32,
+    pub chunks: UVec2, //per axis, up to 64
+    index: u32,
+    pending: bool, //waiting on the heightmap to load
+    baked_resolution: UVec2,
+    range: Vec2, //lowest and highest baked height
+    step: f32, //one over the steepest slope's stretch, keeps the height difference a lower bound
+    #[reflect(ignore)]
+    heights: Arc<Vec<f32>>,
+    #[reflect(ignore)]
+    nodes: Arc<Vec<BvhNode>>,
+}
+
+impl Default for SdTerrain {
+    fn default() -> Self {
+        Self {
+            colour: vec3(0.35, 0.45, 0.25),
+            size: vec2(30.0, 30.0),
+            height: 6.0,
+            heightmap: None,
+            resolution: uvec2(128, 128),
+            frequency: 0.05,
+            octaves: 5,
+            seed: 0,
+            chunks: uvec2(8, 8),
+            index: 0,
+            pending: false,
+            baked_resolution: UVec2::ZERO,
+            range: Vec2::ZERO,
+            step: 1.0,
+            heights: Arc::default(),
+            nodes: Arc::default(),
+        }
+    }
+}
+
+impl SdTerrain {
+    // fills the samples from the heightmap or the noise, then measures the slopes and builds the chunk tree.
+    // false if the heightmap hasn't loaded yet
+    pub fn bake(&mut self, images: &Assets<Image>) -> bool {
+        let (resolution, samples) = match self.heightmap.as_ref().map(|handle| images.get(handle)) {
+            Some(None) => {
+                self.pending = true;
+                return false;
+            }
+            Some(Some(image)) => heightmap_samples(image).unwrap_or_else(|| {
+                warn!("unsupported heightmap format {:?}, using noise instead", image.texture_descriptor.format);
+                self.noise_samples()
+            }),
+            None => self.noise_samples(),
+        };
+        self.pending = false;
+
+        let cell = 2.0 * self.size / (resolution - 1).as_vec2();
+        let at = |x: u32, z: u32| samples[(z * resolution.x + x) as usize];
+
+        // bilinear patches never get steeper than their edges, so the steepest edge bounds the gradient
+        let mut slope = Vec2::ZERO;
+        for z in 0..resolution.y {
+            for x in 0..resolution.x {
+                if x + 1 < resolution.x {
+                    slope.x = slope.x.max((at(x + 1, z) - at(x, z)).abs() * self.height / cell.x);
+                }
+                if z + 1 < resolution.y {
+                    slope.y = slope.y.max((at(x, z + 1) - at(x, z)).abs() * self.height / cell.y);
+                }
+            }
+        }
+        self.step = 1.0 / (1.0 + slope.length_squared()).sqrt();
+
+        let chunks = self.chunks.clamp(UVec2::ONE, UVec2::splat(64)).min(resolution - 1);
+        let mut leaves: Vec<(UVec2, Aabb)> = vec![];
+        let mut range = vec2(f32::MAX, f32::MIN);
+        for cz in 0..chunks.y {
+            for cx in 0..chunks.x {
+                let (x0, x1) = (cx * (resolution.x - 1) / chunks.x, (cx + 1) * (resolution.x - 1) / chunks.x);
+                let (z0, z1) = (cz * (resolution.y - 1) / chunks.y, (cz + 1) * (resolution.y - 1) / chunks.y);
+                let mut low = f32::MAX;
+                let mut high = f32::MIN;
+                for z in z0..=z1 {
+                    for x in x0..=x1 {
+                        low = low.min(at(x, z));
+                        high = high.max(at(x, z));
+                    }
+                }
+                range = vec2(range.x.min(low), range.y.max(high));
+                let corner = |x: u32, z: u32| vec2(x as f32, z as f32) * cell - self.size;
+                let (min, max) = (corner(x0, z0), corner(x1, z1));
+                leaves.push((uvec2(1, cz * chunks.x + cx), Aabb{
+                    min: vec3(min.x, low * self.height - 0.01, min.y),
+                    max: vec3(max.x, high * self.height + 0.01, max.y),
+                }));
+            }
+        }
+        let mut nodes = vec![];
+        build_node(&mut nodes, &mut leaves, 0);
+
+        self.baked_resolution = resolution;
+        self.range = range * self.height;
+        self.heights = Arc::new(samples);
+        self.nodes = Arc::new(nodes);
+        true
+    }
+
+    // world height of the surface straight above or below a world position, None off the footprint or before baking
+    pub fn height_at(&self, transform: &GlobalTransform, position: Vec3) -> Option<f32> {
+        let local = transform.affine().inverse().transform_point3(position);
+        let height = self.local_height(vec2(local.x, local.z))?;
+        Some(transform.transform_point(vec3(local.x, height, local.z)).y)
+    }
+
+    // the same bilinear lookup as SdfTerrain in the shader
+    fn local_height(&self, xz: Vec2) -> Option<f32> {
+        let resolution = self.baked_resolution;
+        if resolution.x < 2 || resolution.y < 2 || xz.abs().cmpgt(self.size).any() {
+            return None;
+        }
+        let uv = (xz / self.size * 0.5 + 0.5) * (resolution - 1).as_vec2();
+        let cell = uv.as_uvec2().min(resolution - 2);
+        let f = uv - cell.as_vec2();
+        let i = (cell.y * resolution.x + cell.x) as usize;
+        let row = resolution.x as usize;
+        let h = &self.heights;
+        let near = h[i] + (h[i + 1] - h[i]) * f.x;
+        let far = h[i + row] + (h[i + row + 1] - h[i + row]) * f.x;
+        Some((near + (far - near) * f.y) * self.height)
+    }
+
+    fn noise_samples(&self) -> (UVec2, Vec<f32>) {
+        let resolution = self.resolution.max(UVec2::splat(2));
+        let mut samples = Vec::with_capacity((resolution.x * resolution.y) as usize);
+        for z in 0..resolution.y {
+            for x in 0..resolution.x {
+                let local = (vec2(x as f32, z as f32) / (resolution - 1).as_vec2() * 2.0 - 1.0) * self.size;
+                samples.push(fractal_noise(local * self.frequency, self.octaves, self.seed));
+            }
+        }
+        (resolution, samples)
+    }
+
+    fn shape(&self, gt: &GlobalTransform) -> TerrainShape {
+        let transform = gt.compute_matrix();
+        TerrainShape{
+            index: self.index,
+            colour: self.colour,
+            size: self.size,
+            height: self.height,
+            range: self.range,
+            resolution: self.baked_resolution,
+            step: self.step,
+            transform_determinant: transform.determinant(),
+            inverse_transform: transform.inverse(),
+            ..Default::default()
+        }
+    }
+
+    fn data(&self, entity: Entity) -> TerrainData {
+        TerrainData{entity, heights: Arc::clone(&self.heights), nodes: Arc::clone(&self.nodes)}
+    }
+}
+
+#[derive(ShaderType, Default, Debug, Clone, Copy)]
+struct TerrainShape{
+    index: u32,
+    colour: Vec3,
+    parent_idx: UVec2,
+    size: Vec2,
+    height: f32,
+    range: Vec2,
+    resolution: UVec2,
+    height_offset: u32, //filled in by set_mat_values when the terrains are packed together
+    node_offset: u32,
+    step: f32,
+    transform_determinant: f32,
+    inverse_transform: Mat4,
+}
+
+#[derive(Debug, Clone)]
+struct TerrainData{
+    entity: Entity, //so the remove hook can fix the index of the terrain swapped into the gap
+    heights: Arc<Vec<f32>>,
+    nodes: Arc<Vec<BvhNode>>,
+}
+
+// one shape definition shared by any number of SdInstance entities. the shape's own transform is ignored,
+// each instance places it with its GlobalTransform. a compound is any number of these placed relative to its origin
+#[derive(Asset, TypePath, Debug, Clone)]
+pub enum SdPrototype{
+    Sphere(SdSphere),
+    Cube(SdCube),
+    Ellipse(SdEllipse),
+    Torus(SdTorus),
+    Cylinder(SdCylinder),
+    Cone(SdCone),
+    Compound(Vec<SdPart>),
+}
+
+#[derive(Debug, Clone)]
+pub struct SdPart{
+    pub shape: SdPrototype,
+    pub transform: Transform,
+}
+
+impl SdPrototype {
+    fn shape_type(&self) -> u32 {
+        match self {
+            SdPrototype::Sphere(_) => 1,
+            SdPrototype::Cube(_) => 2,
+            SdPrototype::Ellipse(_) => 3,
+            SdPrototype::Torus(_) => 4,
+            SdPrototype::Cylinder(_) => 5,
+            SdPrototype::Cone(_) => 6,
+            SdPrototype::Compound(_) => 10,
+        }
+    }
+
+    // the definition moved to wherever the transform puts it
+    fn aabb(&self, inverse_transform: Mat4) -> Aabb {
+        match self {
+            SdPrototype::Sphere(s) => generate_sphere_aabb(SdSphere{inverse_transform, ..*s}),
+            SdPrototype::Cube(s) => generate_cube_aabb(SdCube{inverse_transform, ..*s}),
+            SdPrototype::Ellipse(s) => generate_ellipse_aabb(SdEllipse{inverse_transform, ..*s}),
+            SdPrototype::Torus(s) => generate_torus_aabb(SdTorus{inverse_transform, ..*s}),
+            SdPrototype::Cylinder(s) => generate_cylinder_aabb(SdCylinder{inverse_transform, ..*s}),
+            SdPrototype::Cone(s) => generate_cone_aabb(SdCone{inverse_transform, ..*s}),
+            SdPrototype::Compound(parts) => parts.iter().fold(Aabb::default(), |aabb, part| {
+                aabb_union(aabb, part.shape.aabb(part.transform.compute_matrix().inverse() * inverse_transform))
+            }),
+        }
+    }
+
+    // nested compounds flattened into single shapes with their transforms relative to the top prototype
+    fn flatten(&self, transform: Mat4, parts: &mut Vec<(SdPrototype, Mat4)>) {
+        match self {
+            SdPrototype::Compound(children) => {
+                for child in children {
+                    child.shape.flatten(transform * child.transform.compute_matrix(), parts);
+                }
+            }
+            shape => parts.push((shape.clone(), transform.inverse())),
+        }
+    }
+}
+
+// the bottom level of the two level tree. a prototype's parts and their tree are built once in its own space,
+// the main tree only holds one leaf per instance, so moving an instance refits that leaf whatever it's made of
+#[derive(Default, Debug, Clone)]
+struct Blas{
+    parts: Vec<(SdPrototype, Mat4)>, //single shapes and their inverse transforms within the prototype
+    nodes: Vec<BvhNode>, //root first, leaves point at parts, child indices relative to the root like a terrain's
+}
+
+impl Blas {
+    fn new(prototype: &SdPrototype) -> Self {
+        let mut parts = vec![];
+        prototype.flatten(Mat4::IDENTITY, &mut parts);
+        let mut leaves: Vec<(UVec2, Aabb)> = parts.iter().enumerate()
+            .map(|(i, (shape, inverse_transform))| (uvec2(shape.shape_type(), i as u32), shape.aabb(*inverse_transform)))
+            .collect();
+        let mut nodes = vec![];
+        if !leaves.is_empty() {
+            build_node(&mut nodes, &mut leaves, 0);
+        }
+        Self{parts, nodes}
+    }
+
+    // a lone part keeps its own tight box, otherwise the corners of the root box are moved out by the instance
+    fn aabb(&self, inverse_transform: Mat4) -> Aabb {
+        if let [(shape, part_inverse)] = self.parts.as_slice() {
+            return shape.aabb(*part_inverse * inverse_transform);
+        }
+        let Some(root) = self.nodes.first() else {
+            let centre = inverse_transform.inverse().w_axis.truncate();
+            return Aabb{min: centre, max: centre};
+        };
+        let transform = inverse_transform.inverse();
+        (0..8).fold(Aabb::default(), |aabb, corner| {
+            let local = vec3(
+                if corner & 1 == 0 { root.aabb.min.x } else { root.aabb.max.x },
+                if corner & 2 == 0 { root.aabb.min.y } else { root.aabb.max.y },
+                if corner & 4 == 0 { root.aabb.min.z } else { root.aabb.max.z },
+            );
+            let world = transform.transform_point3(local);
+            Aabb{min: aabb.min.min(world), max: aabb.max.max(world)}
+        })
+    }
+}
+
+// every prototype's parts packed once, in their shape arrays and with the compounds' trees back to back. only
+// rebuilt when a prototype changes, the slots are relative to where set_mat_values puts the parts
+#[derive(Default, Debug, Clone)]
+struct PrototypeParts{
+    spheres: Vec<SdSphere>,
+    cubes: Vec<SdCube>,
+    ellipses: Vec<SdEllipse>,
+    toruses: Vec<SdTorus>,
+    cylinders: Vec<SdCylinder>,
+    cones: Vec<SdCone>,
+    blas_nodes: Vec<BvhNode>,
+    slots: HashMap<AssetId<SdPrototype>, UVec2>, //the lone part, or (10, root in blas_nodes)
+    changed: bool, //blas_nodes needs uploading again
+}
+
+impl PrototypeParts {
+    fn new(prototypes: &HashMap<AssetId<SdPrototype>, Blas>) -> Self {
+        let mut packed = Self{changed: true, ..Default::default()};
+        for (id, blas) in prototypes.iter() {
+            let mut slots = vec![];
+            for (shape, inverse_transform) in &blas.parts {
+                let (inverse_transform, transform_determinant) = (*inverse_transform, 1.0 / inverse_transform.determinant());
+                let slot = match shape {
+                    SdPrototype::Sphere(s) => { packed.spheres.push(SdSphere{transform_determinant, inverse_transform, ..*s}); packed.spheres.len() }
+                    SdPrototype::Cube(s) => { packed.cubes.push(SdCube{transform_determinant, inverse_transform, ..*s}); packed.cubes.len() }
+                    SdPrototype::Ellipse(s) => { packed.ellipses.push(SdEllipse{transform_determinant, inverse_transform, ..*s}); packed.ellipses.len() }
+                    SdPrototype::Torus(s) => { packed.toruses.push(SdTorus{transform_determinant, inverse_transform, ..*s}); packed.toruses.len() }
+                    SdPrototype::Cylinder(s) => { packed.cylinders.push(SdCylinder{transform_determinant, inverse_transform, ..*s}); packed.cylinders.len() }
+                    SdPrototype::Cone(s) => { packed.cones.push(SdCone{transform_determinant, inverse_transform, ..*s}); packed.cones.len() }
+                    SdPrototype::Compound(_) => unreachable!("compounds are flattened when the blas is built"),
+                };
+                slots.push(uvec2(shape.shape_type(), slot as u32 - 1));
+            }
+            match slots.as_slice() {
+                [] => {}
+                [slot] => { packed.slots.insert(*id, *slot); }
+                _ => {
+                    packed.slots.insert(*id, uvec2(10, packed.blas_nodes.len() as u32));
+                    packed.blas_nodes.extend(blas.nodes.iter().map(|node| match node.child1.x {
+                        0 => *node,
+                        _ => BvhNode{child1: slots[node.child1.y as usize], ..*node},
+                    }));
+                }
+            }
+        }
+        packed
+    }
+}
+
+// places a prototype, only the handle and the transform are stored per instance
+#[derive(Component, Default, Debug, Clone, Reflect)]
+pub struct SdInstance{
+    pub prototype: Handle<SdPrototype>,
     index: u32,
-    colour: Vec3,
+}
+
+impl SdInstance {
+    pub fn new(prototype: Handle<SdPrototype>) -> Self {
+        Self{prototype, index: 0}
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+struct ShapeInstance{
+    entity: Entity, //so the remove hook can fix the index of the instance swapped into the gap
     parent_idx: UVec2,
-    pub height: f32,
-    pub radius: f32,
+    prototype: AssetId<SdPrototype>,
     transform_determinant: f32,
     inverse_transform: Mat4,
 }
 
-#[derive(Component, ShaderType, Default, Debug, Clone, Copy, Reflect)]
-pub struct SdCone{
-    index: u32,
-    colour: Vec3,
-    parent_idx: UVec2,
-    pub height: f32,
-    pub sincos: Vec2,
+impl ShapeInstance {
+    fn new(entity: Entity, instance: &SdInstance, gt: &GlobalTransform) -> Self {
+        let transform = gt.compute_matrix();
+        Self{
+            entity,
+            parent_idx: UVec2::ZERO,
+            prototype: instance.prototype.id(),
+            transform_determinant: transform.determinant(),
+            inverse_transform: transform.inverse(),
+        }
+    }
+}
+
+// shape points at the prototype's slot among the packed parts, or is (10, root in blas_nodes) for a compound. filled in by set_mat_values
+#[derive(ShaderType, Default, Debug, Clone, Copy)]
+struct GpuInstance{
+    shape: UVec2,
     transform_determinant: f32,
     inverse_transform: Mat4,
 }
 
+// red channel of the common 8, 16 and 32 bit formats, rows going from -z to +z
+fn heightmap_samples(image: &Image) -> Option<(UVec2, Vec<f32>)> {
+    let size = image.size();
+    let texels = (size.x * size.y) as usize;
+    if size.x < 2 || size.y < 2 || image.data.len() % texels != 0 {
+        return None;
+    }
+    let stride = image.data.len() / texels;
+    let samples: Vec<f32> = match image.texture_descriptor.format {
+        TextureFormat::R8Unorm | TextureFormat::Rg8Unorm | TextureFormat::Rgba8Unorm | TextureFormat::Rgba8UnormSrgb => {
+            image.data.chunks_exact(stride).map(|texel| texel[0] as f32 / 255.0).collect()
+        }
+        TextureFormat::Bgra8Unorm | TextureFormat::Bgra8UnormSrgb => {
+            image.data.chunks_exact(stride).map(|texel| texel[2] as f32 / 255.0).collect()
+        }
+        TextureFormat::R16Unorm | TextureFormat::Rgba16Unorm => {
+            image.data.chunks_exact(stride).map(|texel| u16::from_le_bytes([texel[0], texel[1]]) as f32 / 65535.0).collect()
+        }
+        TextureFormat::R32Float | TextureFormat::Rgba32Float => {
+            image.data.chunks_exact(stride).map(|texel| f32::from_le_bytes([texel[0], texel[1], texel[2], texel[3]])).collect()
+        }
+        _ => return None,
+    };
+    Some((size, samples))
+}
+
+// same hash as the shader's
+fn pcg_hash(v: u32) -> u32 {
+    let state = v.wrapping_mul(747796405).wrapping_add(2891336453);
+    let word = ((state >> ((state >> 28) + 4)) ^ state).wrapping_mul(277803737);
+    (word >> 22) ^ word
+}
+
+fn value_noise_2d(p: Vec2, seed: u32) -> f32 {
+    let c = p.floor();
+    let f = p - c;
+    let u = f * f * (3.0 - 2.0 * f);
+    let lattice = |x: f32, z: f32| {
+        let h = pcg_hash((c.x + x) as i32 as u32 ^ pcg_hash((c.y + z) as i32 as u32 ^ pcg_hash(seed)));
+        h as f32 / u32::MAX as f32
+    };
+    let near = lattice(0.0, 0.0) + (lattice(1.0, 0.0) - lattice(0.0, 0.0)) * u.x;
+    let far = lattice(0.0, 1.0) + (lattice(1.0, 1.0) - lattice(0.0, 1.0)) * u.x;
+    near + (far - near) * u.y
+}
+
+// octaves of value noise at doubling frequency and halving amplitude, normalized back to 0..1
+fn fractal_noise(p: Vec2, octaves: u32, seed: u32) -> f32 {
+    let mut value = 0.0;
+    let mut amplitude = 0.5;
+    let mut total = 0.0;
+    let mut p = p;
+    for octave in 0..octaves.max(1) {
+        value += value_noise_2d(p, seed.wrapping_add(octave)) * amplitude;
+        total += amplitude;
+        amplitude *= 0.5;
+        p *= 2.0;
+    }
+    value / total
+}
+
 
 //bvh functions
 
@@ -861,7 +3643,7 @@ fn generate_sphere_aabb(sphere: SdSphere) -> Aabb{
 
     let (smin, smax) = obb.compute_aabb();
 
-    Aabb{min: smin, max: smax}
+    repeat_aabb(deform_aabb(pad_aabb(Aabb{min: smin, max: smax}, displacement_padding(sphere.displacement, srt.0) + shell_padding(sphere.rounding, sphere.onion, srt.0)), sphere.deform, srt), sphere.repeat, srt)
 }
 
 fn generate_cube_aabb(cube: SdCube) -> Aabb{
@@ -873,7 +3655,7 @@ fn generate_cube_aabb(cube: SdCube) -> Aabb{
     };
     let (smin, smax) = obb.compute_aabb();
 
-    Aabb{min: smin, max: smax}
+    repeat_aabb(deform_aabb(pad_aabb(Aabb{min: smin, max: smax}, displacement_padding(cube.displacement, srt.0) + shell_padding(cube.rounding, cube.onion, srt.0)), cube.deform, srt), cube.repeat, srt)
 }
 
 fn generate_ellipse_aabb(ellipse: SdEllipse) -> Aabb{
@@ -885,7 +3667,7 @@ fn generate_ellipse_aabb(ellipse: SdEllipse) -> Aabb{
     };
     let (smin, smax) = obb.compute_aabb();
 
-    Aabb{min: smin, max: smax}
+    repeat_aabb(deform_aabb(pad_aabb(Aabb{min: smin, max: smax}, displacement_padding(ellipse.displacement, srt.0) + shell_padding(ellipse.rounding, ellipse.onion, srt.0)), ellipse.deform, srt), ellipse.repeat, srt)
 }
 
 fn generate_torus_aabb(torus: SdTorus) -> Aabb{
@@ -897,7 +3679,7 @@ fn generate_torus_aabb(torus: SdTorus) -> Aabb{
     };
     let (smin, smax) = obb.compute_aabb();
 
-    Aabb{min: smin, max: smax}
+    repeat_aabb(deform_aabb(pad_aabb(Aabb{min: smin, max: smax}, displacement_padding(torus.displacement, srt.0) + shell_padding(torus.rounding, torus.onion, srt.0)), torus.deform, srt), torus.repeat, srt)
 }
 
 fn generate_cylinder_aabb(cylinder: SdCylinder) -> Aabb {
@@ -913,7 +3695,7 @@ fn generate_cylinder_aabb(cylinder: SdCylinder) -> Aabb {
     };
     let (smin, smax) = obb.compute_aabb();
 
-    Aabb { min: smin, max: smax }
+    repeat_aabb(deform_aabb(pad_aabb(Aabb{min: smin, max: smax}, displacement_padding(cylinder.displacement, srt.0) + shell_padding(cylinder.rounding, cylinder.onion, srt.0)), cylinder.deform, srt), cylinder.repeat, srt)
 }
 fn generate_cone_aabb(cone: SdCone) -> Aabb {
     let srt = cone.inverse_transform.inverse().to_scale_rotation_translation();
@@ -929,7 +3711,89 @@ fn generate_cone_aabb(cone: SdCone) -> Aabb {
     };
     let (smin, smax) = obb.compute_aabb();
 
-    Aabb { min: smin, max: smax }
+    repeat_aabb(deform_aabb(pad_aabb(Aabb{min: smin, max: smax}, displacement_padding(cone.displacement, srt.0) + shell_padding(cone.rounding, cone.onion, srt.0)), cone.deform, srt), cone.repeat, srt)
+}
+
+fn generate_fog_volume_aabb(fog_volume: SdFogVolume) -> Aabb{
+    let srt = fog_volume.inverse_transform.inverse().to_scale_rotation_translation();
+    let size = if fog_volume.shape == 1 { Vec3::splat(fog_volume.size.x) } else { fog_volume.size };
+    let obb = Obb{
+        center: srt.2,
+        size: size * srt.0,
+        rotation: srt.1,
+    };
+    let (smin, smax) = obb.compute_aabb();
+
+    Aabb{min: smin, max: smax}
+}
+
+// the furthest a displacement can push the surface out, in world units
+fn displacement_padding(displacement: SdDisplacement, scale: Vec3) -> f32 {
+    displacement.amplitude.abs() * scale.max_element()
+}
+
+// a finite grid is the single copy's box swept over the offsets of the outermost copies. an infinite one
+// collapses to a point at the shape so the tree ignores it, the shader marches it from the unbounded list
+fn repeat_aabb(a: Aabb, repeat: ShapeRepeat, srt: (Vec3, Quat, Vec3)) -> Aabb{
+    let (scale, rotation, origin) = srt;
+    match repeat.mode {
+        1 => {
+            let half = (repeat.count.max(UVec3::ONE) - 1).as_vec3() * 0.5 * repeat.spacing * scale;
+            let mut reach = Vec3::ZERO;
+            for corner in [vec3(1.0, 1.0, 1.0), vec3(-1.0, 1.0, 1.0), vec3(1.0, -1.0, 1.0), vec3(1.0, 1.0, -1.0)] {
+                reach = reach.max((rotation * (half * corner)).abs());
+            }
+            Aabb { min: a.min - reach, max: a.max + reach }
+        }
+        2 => Aabb { min: origin, max: origin },
+        _ => a,
+    }
+}
+
+// twisting and bending rotate points around the shape's origin, so they stay within the sphere through its
+// furthest corner. taper and shear can push them further out, by at most their amount times that radius
+fn deform_aabb(a: Aabb, deform: SdDeform, srt: (Vec3, Quat, Vec3)) -> Aabb{
+    if deform.twist == 0.0 && deform.bend == 0.0 && deform.taper == 0.0 && deform.shear == Vec2::ZERO {
+        return a;
+    }
+    let (scale, _, origin) = srt;
+    let mut radius = (a.max - origin).max(origin - a.min).length();
+    radius *= 1.0 + deform.taper.abs() * radius / scale.min_element().max(1e-4);
+    radius *= 1.0 + deform.shear.length();
+    aabb_union(a, Aabb { min: origin - radius, max: origin + radius })
+}
+
+// rounding grows the surface by its radius and the onion shells reach out by at most twice the thickness
+fn shell_padding(rounding: f32, onion: f32, scale: Vec3) -> f32 {
+    (rounding.max(0.0) + 2.0 * onion.abs()) * scale.max_element()
+}
+
+fn pad_aabb(a: Aabb, padding: f32) -> Aabb{
+    Aabb { min: a.min - padding, max: a.max + padding }
+}
+
+// an instance whose prototype hasn't loaded is a point, refit once sync_prototypes sees it
+fn generate_instance_aabb(instance: ShapeInstance, prototypes: &HashMap<AssetId<SdPrototype>, Blas>) -> Aabb{
+    match prototypes.get(&instance.prototype) {
+        Some(blas) => blas.aabb(instance.inverse_transform),
+        None => {
+            let centre = instance.inverse_transform.inverse().w_axis.truncate();
+            Aabb{min: centre, max: centre}
+        }
+    }
+}
+
+fn generate_terrain_aabb(terrain: TerrainShape) -> Aabb{
+    let srt = terrain.inverse_transform.inverse().to_scale_rotation_translation();
+    let centre = vec3(0.0, (terrain.range.x + terrain.range.y) * 0.5, 0.0);
+    let obb = Obb{
+        center: srt.2 + srt.1 * (centre * srt.0),
+        size: vec3(terrain.size.x, (terrain.range.y - terrain.range.x) * 0.5 + 0.01, terrain.size.y) * srt.0,
+        rotation: srt.1,
+    };
+    let (smin, smax) = obb.compute_aabb();
+
+    Aabb{min: smin, max: smax}
 }
 
 fn aabb_union(a: Aabb, b: Aabb) -> Aabb{
@@ -944,11 +3808,8 @@ fn aabb_area(a: Aabb) -> f32 {
 fn refit_leaf(shape_idx: UVec2, 
     container: &ShapeContainer, 
     nodes: Arc<Mutex<Vec<BvhNode>>>, 
-    root_index: Arc<Mutex<u32>>,
 ){
 
-    let loop_count: u32 = 4;
-    let root = *root_index.lock().unwrap();
     let mut nodes_1 = nodes.lock().unwrap();
     let spheres =  container.spheres.lock().unwrap();
     let cubes =  container.cubes.lock().unwrap();
@@ -956,6 +3817,10 @@ fn refit_leaf(shape_idx: UVec2,
     let toruses = container.toruses.lock().unwrap();
     let cylinders = container.cylinders.lock().unwrap();
     let cones = container.cones.lock().unwrap();
+    let fog_volumes = container.fog_volumes.lock().unwrap();
+    let terrains = container.terrains.lock().unwrap();
+    let instances = container.instances.lock().unwrap();
+    let prototypes = container.prototypes.lock().unwrap();
 
     let leaf_index = match shape_idx.x{
         1 => spheres[shape_idx.y as usize].parent_idx.y,
@@ -964,6 +3829,9 @@ fn refit_leaf(shape_idx: UVec2,
         4 => toruses[shape_idx.y as usize].parent_idx.y,
         5 => cylinders[shape_idx.y as usize].parent_idx.y,
         6 => cones[shape_idx.y as usize].parent_idx.y,
+        7 => fog_volumes[shape_idx.y as usize].parent_idx.y,
+        8 => terrains[shape_idx.y as usize].parent_idx.y,
+        9 => instances[shape_idx.y as usize].parent_idx.y,
         _ => panic!("the shape idx was {}", shape_idx.y),
     };
 
@@ -975,6 +3843,9 @@ fn refit_leaf(shape_idx: UVec2,
         4 => generate_torus_aabb(toruses[shape_idx.y as usize]),
         5 => generate_cylinder_aabb(cylinders[shape_idx.y as usize]),
         6 => generate_cone_aabb(cones[shape_idx.y as usize]),
+        7 => generate_fog_volume_aabb(fog_volumes[shape_idx.y as usize]),
+        8 => generate_terrain_aabb(terrains[shape_idx.y as usize]),
+        9 => generate_instance_aabb(instances[shape_idx.y as usize], &prototypes),
         _ => panic!("the shape idx was {}", shape_idx),
     };
 
@@ -995,61 +3866,6 @@ fn refit_leaf(shape_idx: UVec2,
 
         
 
-        if refit_parent_idx == root{
-            //refit_parent_idx = nodes_1[refit_parent_idx as usize].o_p_idx.y;
-            break; //return if root or leaf
-        }
-    
-        
-        let node_parent = nodes_1[refit_parent_idx as usize].o_p_idx.y;
-        let cost1 = aabb_area(nodes_1[node_parent as usize].aabb);
-        
-        let node_sibling = if nodes_1[node_parent as usize].child1 == uvec2(0, refit_parent_idx) {
-            nodes_1[node_parent as usize].child2.y
-        }else{
-            nodes_1[node_parent as usize].child1.y
-        };
-        
-    
-        let limit = usize::max(nodes_1.len() - 2 - loop_count as usize, 0); 
-    
-        let mut i = nodes_1.len() - 2;
-    
-        while i > 0 || i > limit{
-            if i == root as usize || nodes_1[i].child1.x != 0{
-                i = i.saturating_sub(2);
-                continue; //skips root and leaf nodes
-            }
-    
-            let i_parent = nodes_1[i].o_p_idx.y;
-    
-            let cost2 = aabb_area(nodes_1[i_parent as usize].aabb);
-            let cost3 = cost1 + cost2;
-    
-            let i_sibling = if nodes_1[i_parent as usize].child1 == uvec2(0, i as u32) {
-                nodes_1[i_parent as usize].child2.y
-            }else{
-                nodes_1[i_parent as usize].child1.y
-            };
-    
-            let cost4 = aabb_area(aabb_union(nodes_1[refit_parent_idx as usize].aabb, nodes_1[i_sibling as usize].aabb)) + aabb_area(aabb_union(nodes_1[i].aabb, nodes_1[node_sibling as usize].aabb));
-    
-    
-            if cost4 <= cost3 {
-                nodes_1.swap(i, refit_parent_idx as usize);
-                nodes_1[i_parent as usize].aabb = aabb_union(nodes_1[refit_parent_idx as usize].aabb, nodes_1[i_sibling as usize].aabb);
-                nodes_1[node_parent as usize].aabb = aabb_union(nodes_1[i].aabb, nodes_1[node_sibling as usize].aabb);
-    
-            }
-    
-            i = i.saturating_sub(2);
-            
-        }
-    
-       
-
-
-
         refit_parent_idx = nodes_1[refit_parent_idx as usize].o_p_idx.y;
     }
 
@@ -1059,11 +3875,11 @@ fn refit_leaf(shape_idx: UVec2,
     drop(toruses);
     drop(cylinders);
     drop(cones);
+    drop(fog_volumes);
+    drop(terrains);
+    drop(instances);
+    drop(prototypes);
     drop(nodes_1);
-    
-
-    //maybe do tree rotations
-
 }
 
 
@@ -1082,6 +3898,10 @@ fn insert_leaf(
     let mut toruses = container.toruses.lock().unwrap();
     let mut cylinders = container.cylinders.lock().unwrap();
     let mut cones = container.cones.lock().unwrap();
+    let mut fog_volumes = container.fog_volumes.lock().unwrap();
+    let mut terrains = container.terrains.lock().unwrap();
+    let mut instances = container.instances.lock().unwrap();
+    let prototypes = container.prototypes.lock().unwrap();
     let aabb = match shape_idx.x{
         
         1 => generate_sphere_aabb(spheres[shape_idx.y as usize]),
@@ -1090,6 +3910,9 @@ fn insert_leaf(
         4 => generate_torus_aabb(toruses[shape_idx.y as usize]),
         5 => generate_cylinder_aabb(cylinders[shape_idx.y as usize]),
         6 => generate_cone_aabb(cones[shape_idx.y as usize]),
+        7 => generate_fog_volume_aabb(fog_volumes[shape_idx.y as usize]),
+        8 => generate_terrain_aabb(terrains[shape_idx.y as usize]),
+        9 => generate_instance_aabb(instances[shape_idx.y as usize], &prototypes),
         _ => panic!("the shape idx was {}", shape_idx),
     };
 
@@ -1109,12 +3932,24 @@ fn insert_leaf(
 
     if *node_count_1 == 0 {
         *root_index_1 = 1; 
+        nodes_1.clear();
         nodes_1.push(BvhNode::default()); 
         leaf.o_p_idx = uvec2(1, 0); 
         nodes_1.push(leaf);
         
         *node_count_1 += 1;
-        println!("returned!");
+        match shape_idx.x{
+            1 => spheres[shape_idx.y as usize].parent_idx = uvec2(0, 1),
+            2 => cubes[shape_idx.y as usize].parent_idx = uvec2(0, 1),
+            3 => ellipses[shape_idx.y as usize].parent_idx = uvec2(0, 1),
+            4 => toruses[shape_idx.y as usize].parent_idx = uvec2(0, 1),
+            5 => cylinders[shape_idx.y as usize].parent_idx = uvec2(0, 1),
+            6 => cones[shape_idx.y as usize].parent_idx = uvec2(0, 1),
+            7 => fog_volumes[shape_idx.y as usize].parent_idx = uvec2(0, 1),
+            8 => terrains[shape_idx.y as usize].parent_idx = uvec2(0, 1),
+            9 => instances[shape_idx.y as usize].parent_idx = uvec2(0, 1),
+            _ => panic!("the shape idx was {}", shape_idx),
+        }
         return;
     }   
     drop(node_count_1);
@@ -1173,6 +4008,9 @@ fn insert_leaf(
         4 => toruses[shape_idx.y as usize].parent_idx = uvec2(0, leaf_index),
         5 => cylinders[shape_idx.y as usize].parent_idx = uvec2(0, leaf_index),
         6 => cones[shape_idx.y as usize].parent_idx = uvec2(0, leaf_index),
+        7 => fog_volumes[shape_idx.y as usize].parent_idx = uvec2(0, leaf_index),
+        8 => terrains[shape_idx.y as usize].parent_idx = uvec2(0, leaf_index),
+        9 => instances[shape_idx.y as usize].parent_idx = uvec2(0, leaf_index),
         _ => panic!("the shape idx was {}", shape_idx),
         
     }
@@ -1187,8 +4025,6 @@ fn insert_leaf(
 
     //refit the tree
 
-    let loop_count: u32 = 4;
-    let root = *root_index.lock().unwrap();
     let mut nodes_1 = nodes.lock().unwrap();
      
     let mut refit_parent_idx = nodes_1[leaf_index as usize].o_p_idx.y;
@@ -1200,49 +4036,6 @@ fn insert_leaf(
         //println!("just checking");
         nodes_1[refit_parent_idx as usize].aabb = aabb_union(aabb1, aabb2);
 
-        if refit_parent_idx == root || refit_parent_idx == 0{
-            //refit_parent_idx = nodes_1[refit_parent_idx as usize].o_p_idx.y;
-            break; //return if root or leaf
-        }
-
-        let node_parent = nodes_1[refit_parent_idx as usize].o_p_idx.y;
-        let cost1 = aabb_area(nodes_1[node_parent as usize].aabb);
-        
-        let node_sibling = if nodes_1[node_parent as usize].child1 == uvec2(0, refit_parent_idx) {
-            nodes_1[node_parent as usize].child2.y
-        }else{
-            nodes_1[node_parent as usize].child1.y
-        };
-        
-    
-        let limit = usize::max(nodes_1.len() - 2 - loop_count as usize, 0); 
-    
-        let mut i = nodes_1.len() - 2;
-    
-        while i > 0 || i > limit{
-            if i == root as usize || nodes_1[i].child1.x != 0{
-                i = i.saturating_sub(2);
-                continue; //skips root and leaf nodes
-            }
-            let i_parent = nodes_1[i].o_p_idx.y;
-            let cost2 = aabb_area(nodes_1[i_parent as usize].aabb);
-            let cost3 = cost1 + cost2;
-            let i_sibling = if nodes_1[i_parent as usize].child1 == uvec2(0, i as u32) {
-                nodes_1[i_parent as usize].child2.y
-            }else{
-                nodes_1[i_parent as usize].child1.y
-            };
-            let cost4 = aabb_area(aabb_union(nodes_1[refit_parent_idx as usize].aabb, nodes_1[i_sibling as usize].aabb)) + aabb_area(aabb_union(nodes_1[i].aabb, nodes_1[node_sibling as usize].aabb));
-            if cost4 <= cost3 {
-                nodes_1.swap(i, refit_parent_idx as usize);
-                nodes_1[i_parent as usize].aabb = aabb_union(nodes_1[refit_parent_idx as usize].aabb, nodes_1[i_sibling as usize].aabb);
-                nodes_1[node_parent as usize].aabb = aabb_union(nodes_1[i].aabb, nodes_1[node_sibling as usize].aabb);
-    
-            }
-            i = i.saturating_sub(2);
-        }
-
-       
         refit_parent_idx = nodes_1[refit_parent_idx as usize].o_p_idx.y;
     }
     drop(nodes_1);
@@ -1252,6 +4045,10 @@ fn insert_leaf(
     drop(toruses);
     drop(cylinders);
     drop(cones);
+    drop(fog_volumes);
+    drop(terrains);
+    drop(instances);
+    drop(prototypes);
     
 
 
@@ -1366,8 +4163,7 @@ fn prepare_node_removal(
 ) 
 {
 
-    //first, grab node 1's parent and find which child is equal to node1, setting the child to [0, 0] (nothing there)
-    //alternatively, if node1 is the root node, then don't bother fixing children, but make whatever child of it that isn't [0, 0] but is a node, the new root.
+    //node1 has to be unlinked from the tree already (remove_leaf does that), nothing should point at it anymore.
     //grab the last node of the vec, checking that node1 isn't the last node of the vec, if it isn't, save the index of the last vec of the node as node2.
     //then, call the swap_remove function on node 1. if node1 was the last element, return and remove 1 from node_count.
     //grab node2 and fix it's parent and children.
@@ -1375,27 +4171,16 @@ fn prepare_node_removal(
     let mut nodes = nodes_lock.lock().unwrap();
 
     let default = uvec2(0, 0);
-    let child1 = nodes[node1 as usize].child1;
-    let child2 = nodes[node1 as usize].child2;
-    let parent1 = nodes[node1 as usize].o_p_idx.y;
-
-    if parent1 == 0 {   // node1 is root node
-
-        if (child2.x != 0 || child2 == default) && child1 != default{ //if child2 is either not a node or unused AND child1 isn't unused then.. 
-            *root_index.lock().unwrap() = child1.y; //set root index to child1
-
-        }else if (child1.x != 0 || child1 == default) && child2 != default{ //if child1 is either not a node or unused AND child2 isn't unused then.. 
-            *root_index.lock().unwrap() = child2.y; //set root index to child2
-
-        }
-    }
     let last_idx = (nodes.len() - 1) as u32;
     nodes.swap_remove(node1 as usize);
     *node_count.lock().unwrap() -= 1;
     if node1 != last_idx{ //if node2 is not eq
         nodes[node1 as usize].o_p_idx.x = node1;
         let oldparentlast = nodes[node1 as usize].o_p_idx.y;
-        if nodes[oldparentlast as usize].child1 == uvec2(0, last_idx) {
+        if oldparentlast == 0 { //the last node was the root
+            *root_index.lock().unwrap() = node1;
+        }
+        else if nodes[oldparentlast as usize].child1 == uvec2(0, last_idx) {
             nodes[oldparentlast as usize].child1.y = node1;
         }
         else{
@@ -1410,6 +4195,9 @@ fn prepare_node_removal(
         let mut toruses = container.toruses.lock().unwrap();
         let mut cylinders = container.cylinders.lock().unwrap();
         let mut cones = container.cones.lock().unwrap();
+        let mut fog_volumes = container.fog_volumes.lock().unwrap();
+        let mut terrains = container.terrains.lock().unwrap();
+        let mut instances = container.instances.lock().unwrap();
         if oldchild1 != default { //handle swapped child 1
             match oldchild1.x {
                 0 => nodes[oldchild1.y as usize].o_p_idx.y = node1,
@@ -1419,6 +4207,9 @@ fn prepare_node_removal(
                 4 => toruses[oldchild1.y as usize].parent_idx = uvec2(0, node1),
                 5 => cylinders[oldchild1.y as usize].parent_idx = uvec2(0, node1),
                 6 => cones[oldchild1.y as usize].parent_idx = uvec2(0, node1),
+                7 => fog_volumes[oldchild1.y as usize].parent_idx = uvec2(0, node1),
+                8 => terrains[oldchild1.y as usize].parent_idx = uvec2(0, node1),
+                9 => instances[oldchild1.y as usize].parent_idx = uvec2(0, node1),
                 _ => panic!("missing case for node fixing, check the prepare_node_removal function")
             }
         }
@@ -1431,6 +4222,9 @@ fn prepare_node_removal(
                 4 => toruses[oldchild2.y as usize].parent_idx = uvec2(0, node1),
                 5 => cylinders[oldchild2.y as usize].parent_idx = uvec2(0, node1),
                 6 => cones[oldchild2.y as usize].parent_idx = uvec2(0, node1),
+                7 => fog_volumes[oldchild2.y as usize].parent_idx = uvec2(0, node1),
+                8 => terrains[oldchild2.y as usize].parent_idx = uvec2(0, node1),
+                9 => instances[oldchild2.y as usize].parent_idx = uvec2(0, node1),
                 _ => panic!("missing case for node fixing, check the prepare_node_removal function")
             }
         }
@@ -1458,6 +4252,9 @@ fn remove_leaf(
     let toruses = container.toruses.lock().unwrap();
     let cylinders = container.cylinders.lock().unwrap();
     let cones = container.cones.lock().unwrap();
+    let fog_volumes = container.fog_volumes.lock().unwrap();
+    let terrains = container.terrains.lock().unwrap();
+    let instances = container.instances.lock().unwrap();
 
     //validate shape type
 
@@ -1469,6 +4266,9 @@ fn remove_leaf(
         4 => toruses[shape_idx.y as usize].parent_idx,
         5 => cylinders[shape_idx.y as usize].parent_idx,
         6 => cones[shape_idx.y as usize].parent_idx,
+        7 => fog_volumes[shape_idx.y as usize].parent_idx,
+        8 => terrains[shape_idx.y as usize].parent_idx,
+        9 => instances[shape_idx.y as usize].parent_idx,
         _ => panic!("the shape idx was {}", shape_idx),
     };
 
@@ -1482,23 +4282,227 @@ fn remove_leaf(
     drop(toruses);
     drop(cylinders);
     drop(cones);
+    drop(fog_volumes);
+    drop(terrains);
+    drop(instances);
+
+    let mut nodes_1 = nodes.lock().unwrap();
+    let leaf_index = leaf_idx.y;
+    let parent_idx = nodes_1[leaf_index as usize].o_p_idx.y;
+
+    if parent_idx == 0 {
+        //the leaf was the only thing in the tree, go back to the empty tree insert_leaf expects
+        nodes_1.clear();
+        nodes_1.push(BvhNode::default());
+        *node_count.lock().unwrap() = 0;
+        *root_index.lock().unwrap() = 1;
+        return;
+    }
+
+    //the parent goes with the leaf, so hook the sibling straight onto the grandparent
+    let sibling = if nodes_1[parent_idx as usize].child1 == uvec2(0, leaf_index) {
+        nodes_1[parent_idx as usize].child2
+    }else{
+        nodes_1[parent_idx as usize].child1
+    };
+    let grandparent_idx = nodes_1[parent_idx as usize].o_p_idx.y;
+    nodes_1[sibling.y as usize].o_p_idx.y = grandparent_idx;
+    if grandparent_idx == 0 {
+        *root_index.lock().unwrap() = sibling.y;
+    }
+    else if nodes_1[grandparent_idx as usize].child1 == uvec2(0, parent_idx) {
+        nodes_1[grandparent_idx as usize].child1 = sibling;
+    }
+    else {
+        nodes_1[grandparent_idx as usize].child2 = sibling;
+    }
+
+    //refit the tree
+    let mut refit_parent_idx = grandparent_idx;
+    while refit_parent_idx != 0 {
+        let child1 = nodes_1[refit_parent_idx as usize].child1;
+        let child2 = nodes_1[refit_parent_idx as usize].child2;
+        nodes_1[refit_parent_idx as usize].aabb = aabb_union(nodes_1[child1.y as usize].aabb, nodes_1[child2.y as usize].aabb);
+        refit_parent_idx = nodes_1[refit_parent_idx as usize].o_p_idx.y;
+    }
+    drop(nodes_1);
+
+    //remove the higher index first, so the node swapped into its place is never the other one being removed
+    prepare_node_removal(u32::max(leaf_index, parent_idx), Arc::clone(&nodes), Arc::clone(&node_count), Arc::clone(&root_index), container);
+    prepare_node_removal(u32::min(leaf_index, parent_idx), Arc::clone(&nodes), Arc::clone(&node_count), Arc::clone(&root_index), container);
+}
+
+// measures the tree after this frame's inserts, refits and removals, rebuilding it if it got too deep.
+// a rebuild moves every leaf, so the parent indices get copied back onto the components without tripping change detection
+fn balance_bvh(
+    container: Res<ShapeContainer>,
+    tree: Res<BvhTree>,
+    mut spheres: Query<&mut SdSphere>,
+    mut cubes: Query<&mut SdCube>,
+    mut ellipses: Query<&mut SdEllipse>,
+    mut toruses: Query<&mut SdTorus>,
+    mut cylinders: Query<&mut SdCylinder>,
+    mut cones: Query<&mut SdCone>,
+    mut fog_volumes: Query<&mut SdFogVolume>,
+){
+    let mut depth = tree_depth(&tree.nodes.lock().unwrap(), *tree.root_index.lock().unwrap());
 
-    let first_was_root = leaf_idx.y == *root_index.lock().unwrap(); 
+    if depth > MAX_BVH_DEPTH {
+        rebuild_tree(&container, &tree);
+        depth = tree_depth(&tree.nodes.lock().unwrap(), *tree.root_index.lock().unwrap());
 
-    let parent_idx = nodes.lock().unwrap()[leaf_idx.y as usize].o_p_idx.y;
+        let container_spheres = container.spheres.lock().unwrap();
+        for mut sphere in &mut spheres {
+            sphere.bypass_change_detection().parent_idx = container_spheres[sphere.index as usize].parent_idx;
+        }
+        let container_cubes = container.cubes.lock().unwrap();
+        for mut cube in &mut cubes {
+            cube.bypass_change_detection().parent_idx = container_cubes[cube.index as usize].parent_idx;
+        }
+        let container_ellipses = container.ellipses.lock().unwrap();
+        for mut ellipse in &mut ellipses {
+            ellipse.bypass_change_detection().parent_idx = container_ellipses[ellipse.index as usize].parent_idx;
+        }
+        let container_toruses = container.toruses.lock().unwrap();
+        for mut torus in &mut toruses {
+            torus.bypass_change_detection().parent_idx = container_toruses[torus.index as usize].parent_idx;
+        }
+        let container_cylinders = container.cylinders.lock().unwrap();
+        for mut cylinder in &mut cylinders {
+            cylinder.bypass_change_detection().parent_idx = container_cylinders[cylinder.index as usize].parent_idx;
+        }
+        let container_cones = container.cones.lock().unwrap();
+        for mut cone in &mut cones {
+            cone.bypass_change_detection().parent_idx = container_cones[cone.index as usize].parent_idx;
+        }
+        let container_fog_volumes = container.fog_volumes.lock().unwrap();
+        for mut fog_volume in &mut fog_volumes {
+            fog_volume.bypass_change_detection().parent_idx = container_fog_volumes[fog_volume.index as usize].parent_idx;
+        }
+    }
 
-    prepare_node_removal(leaf_idx.y, Arc::clone(&nodes), Arc::clone(&node_count), Arc::clone(&root_index), container);
+    *tree.depth.lock().unwrap() = depth;
+}
 
-    refit_leaf(uvec2(0,parent_idx), container, Arc::clone(&nodes), Arc::clone(&root_index));
+fn tree_depth(nodes: &[BvhNode], root: u32) -> u32 {
+    if nodes.len() <= 1 {
+        return 0; //only the default node, nothing to traverse
+    }
 
-    if !first_was_root{
+    let mut deepest = 0;
+    let mut stack = vec![(root, 1)];
+    while let Some((index, depth)) = stack.pop() {
+        deepest = u32::max(deepest, depth);
+        let node = nodes[index as usize];
+        if node.child1.x != 0 {
+            continue; //leaf
+        }
+        if node.child1 != uvec2(0, 0) {
+            stack.push((node.child1.y, depth + 1));
+        }
+        if node.child2 != uvec2(0, 0) {
+            stack.push((node.child2.y, depth + 1));
+        }
+    }
+    deepest
+}
 
-        prepare_node_removal(parent_idx, Arc::clone(&nodes), Arc::clone(&node_count), Arc::clone(&root_index), container);
+// throws the node vec away and builds it again from every shape, splitting at the median centroid
+// along the longest axis so the depth ends up around log2 of the shape count
+fn rebuild_tree(container: &ShapeContainer, tree: &BvhTree){
+    let mut spheres =  container.spheres.lock().unwrap();
+    let mut cubes =  container.cubes.lock().unwrap();
+    let mut ellipses = container.ellipses.lock().unwrap();
+    let mut toruses = container.toruses.lock().unwrap();
+    let mut cylinders = container.cylinders.lock().unwrap();
+    let mut cones = container.cones.lock().unwrap();
+    let mut fog_volumes = container.fog_volumes.lock().unwrap();
+    let mut terrains = container.terrains.lock().unwrap();
+    let mut instances = container.instances.lock().unwrap();
+    let prototypes = container.prototypes.lock().unwrap();
+
+    let mut leaves: Vec<(UVec2, Aabb)> = vec![];
+    leaves.extend(spheres.iter().enumerate().map(|(i, s)| (uvec2(1, i as u32), generate_sphere_aabb(*s))));
+    leaves.extend(cubes.iter().enumerate().map(|(i, s)| (uvec2(2, i as u32), generate_cube_aabb(*s))));
+    leaves.extend(ellipses.iter().enumerate().map(|(i, s)| (uvec2(3, i as u32), generate_ellipse_aabb(*s))));
+    leaves.extend(toruses.iter().enumerate().map(|(i, s)| (uvec2(4, i as u32), generate_torus_aabb(*s))));
+    leaves.extend(cylinders.iter().enumerate().map(|(i, s)| (uvec2(5, i as u32), generate_cylinder_aabb(*s))));
+    leaves.extend(cones.iter().enumerate().map(|(i, s)| (uvec2(6, i as u32), generate_cone_aabb(*s))));
+    leaves.extend(fog_volumes.iter().enumerate().map(|(i, s)| (uvec2(7, i as u32), generate_fog_volume_aabb(*s))));
+    leaves.extend(terrains.iter().enumerate().map(|(i, s)| (uvec2(8, i as u32), generate_terrain_aabb(*s))));
+    leaves.extend(instances.iter().enumerate().map(|(i, s)| (uvec2(9, i as u32), generate_instance_aabb(*s, &prototypes))));
+
+    let mut nodes = tree.nodes.lock().unwrap();
+    nodes.clear();
+    nodes.push(BvhNode::default());
+
+    if leaves.is_empty() {
+        *tree.node_count.lock().unwrap() = 0;
+        *tree.root_index.lock().unwrap() = 1;
+        return;
+    }
 
+    let root = build_node(&mut nodes, &mut leaves, 0);
+    *tree.root_index.lock().unwrap() = root;
+    *tree.node_count.lock().unwrap() = nodes.len() as u32 - 1;
+
+    for node in nodes.iter().skip(1) {
+        let leaf_idx = uvec2(0, node.o_p_idx.x);
+        match node.child1.x {
+            0 => {}
+            1 => spheres[node.child1.y as usize].parent_idx = leaf_idx,
+            2 => cubes[node.child1.y as usize].parent_idx = leaf_idx,
+            3 => ellipses[node.child1.y as usize].parent_idx = leaf_idx,
+            4 => toruses[node.child1.y as usize].parent_idx = leaf_idx,
+            5 => cylinders[node.child1.y as usize].parent_idx = leaf_idx,
+            6 => cones[node.child1.y as usize].parent_idx = leaf_idx,
+            7 => fog_volumes[node.child1.y as usize].parent_idx = leaf_idx,
+            8 => terrains[node.child1.y as usize].parent_idx = leaf_idx,
+            9 => instances[node.child1.y as usize].parent_idx = leaf_idx,
+            _ => panic!("missing case for leaf fixing, check the rebuild_tree function"),
+        }
     }
+}
 
+fn build_node(nodes: &mut Vec<BvhNode>, leaves: &mut [(UVec2, Aabb)], parent: u32) -> u32 {
+    let index = nodes.len() as u32;
+
+    if leaves.len() == 1 {
+        nodes.push(BvhNode{
+            o_p_idx: uvec2(index, parent),
+            aabb: leaves[0].1,
+            child1: leaves[0].0,
+            ..Default::default()
+        });
+        return index;
+    }
 
+    nodes.push(BvhNode{o_p_idx: uvec2(index, parent), ..Default::default()});
 
+    let mut min = Vec3::splat(f32::MAX);
+    let mut max = Vec3::splat(f32::MIN);
+    for (_, aabb) in leaves.iter() {
+        let centre = (aabb.min + aabb.max) * 0.5;
+        min = min.min(centre);
+        max = max.max(centre);
+    }
+    let extent = max - min;
+    let axis = if extent.x >= extent.y && extent.x >= extent.z { 0 } else if extent.y >= extent.z { 1 } else { 2 };
+
+    let mid = leaves.len() / 2;
+    leaves.select_nth_unstable_by(mid, |a, b| {
+        let ca = (a.1.min + a.1.max)[axis];
+        let cb = (b.1.min + b.1.max)[axis];
+        ca.total_cmp(&cb)
+    });
+    let (left, right) = leaves.split_at_mut(mid);
+
+    let child1 = build_node(nodes, left, index);
+    let child2 = build_node(nodes, right, index);
+    nodes[index as usize].child1 = uvec2(0, child1);
+    nodes[index as usize].child2 = uvec2(0, child2);
+    nodes[index as usize].aabb = aabb_union(nodes[child1 as usize].aabb, nodes[child2 as usize].aabb);
+    index
 }
 
 /* 
@@ -1582,4 +4586,336 @@ fn rotate_tree(
     }
 
 }
-    */
\ No newline at end of file
+    */
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // just the shape bookkeeping, push_shapes and the remove hooks, no rendering
+    fn shape_world() -> (World, Schedule) {
+        let mut world = World::new();
+        world.insert_resource(ShapeContainer::default());
+        world.insert_resource(BvhTree::default());
+        register_sdf_hooks(&mut world);
+        let mut schedule = Schedule::default();
+        schedule.add_systems((push_shapes, balance_bvh).chain());
+        (world, schedule)
+    }
+
+    // the depth walked the slow way, to check tree_depth against
+    fn depth_below(nodes: &[BvhNode], index: u32) -> u32 {
+        let node = nodes[index as usize];
+        if node.child1.x != 0 {
+            return 1;
+        }
+        1 + u32::max(depth_below(nodes, node.child1.y), depth_below(nodes, node.child2.y))
+    }
+
+    // the most entries the shader's traversal stack holds when the ray hits every box. it pops one node and
+    // pushes both children, so the siblings left waiting pile up on the way down
+    fn stack_need(nodes: &[BvhNode], root: u32) -> u32 {
+        let mut stack = vec![root];
+        let mut most = 1;
+        while let Some(index) = stack.pop() {
+            let node = nodes[index as usize];
+            if node.child1.x == 0 {
+                stack.push(node.child2.y);
+                stack.push(node.child1.y);
+                most = most.max(stack.len());
+            }
+        }
+        most as u32
+    }
+
+    // every link points both ways, the leaves and the fog volumes agree and the tree's own depth is right
+    fn check_tree(world: &World) -> u32 {
+        let tree = world.resource::<BvhTree>();
+        let nodes = tree.nodes.lock().unwrap();
+        let root = *tree.root_index.lock().unwrap();
+        let fog_volumes = world.resource::<ShapeContainer>().fog_volumes.lock().unwrap();
+        assert_eq!(*tree.node_count.lock().unwrap() as usize, nodes.len() - 1);
+        if fog_volumes.is_empty() {
+            assert_eq!(tree_depth(&nodes, root), 0);
+            return 0;
+        }
+        assert_eq!(nodes[root as usize].o_p_idx.y, 0);
+
+        let mut leaves = 0;
+        for (i, node) in nodes.iter().enumerate().skip(1) {
+            assert_eq!(node.o_p_idx.x, i as u32);
+            if node.child1.x == 7 {
+                assert_eq!(fog_volumes[node.child1.y as usize].parent_idx, uvec2(0, i as u32));
+                leaves += 1;
+                continue;
+            }
+            for child in [node.child1, node.child2] {
+                let child_node = nodes[child.y as usize];
+                assert_eq!(child_node.o_p_idx.y, i as u32);
+                assert!(node.aabb.min.cmple(child_node.aabb.min).all() && node.aabb.max.cmpge(child_node.aabb.max).all());
+            }
+        }
+        assert_eq!(leaves, fog_volumes.len());
+
+        let depth = tree_depth(&nodes, root);
+        assert_eq!(depth, depth_below(&nodes, root));
+        assert!(bvh_stack_size(depth) >= stack_need(&nodes, root));
+        depth
+    }
+
+    fn spawn_fog_volume(world: &mut World, position: Vec3) -> Entity {
+        world.spawn((SdFogVolume{size: Vec3::splat(0.5), ..Default::default()}, GlobalTransform::from_translation(position))).id()
+    }
+
+    #[test]
+    fn removing_a_fog_volume_fixes_the_moved_index() {
+        let (mut world, mut schedule) = shape_world();
+        let entities: Vec<Entity> = (0..3).map(|i| world.spawn((
+            SdFogVolume{size: Vec3::ONE, ..Default::default()},
+            GlobalTransform::from_xyz(i as f32 * 4.0, 0.0, 0.0),
+        )).id()).collect();
+        schedule.run(&mut world);
+
+        world.despawn(entities[1]);
+        assert_eq!(world.get::<SdFogVolume>(entities[2]).unwrap().index, 1);
+
+        *world.get_mut::<GlobalTransform>(entities[2]).unwrap() = GlobalTransform::from_xyz(20.0, 0.0, 0.0);
+        schedule.run(&mut world);
+
+        let container = world.resource::<ShapeContainer>();
+        let fog_volumes = container.fog_volumes.lock().unwrap();
+        assert_eq!(fog_volumes.len(), 2);
+        assert_eq!(fog_volumes[0].inverse_transform.w_axis.x, 0.0);
+        assert_eq!(fog_volumes[1].inverse_transform.w_axis.x, -20.0);
+
+        let nodes = world.resource::<BvhTree>().nodes.lock().unwrap();
+        let leaf = nodes[fog_volumes[1].parent_idx.y as usize];
+        assert_eq!(leaf.child1, uvec2(7, 1));
+        assert!(leaf.aabb.min.x > 15.0);
+    }
+
+    fn heightmap(width: u32, height: u32, format: TextureFormat, data: Vec<u8>) -> Image {
+        Image::new(
+            Extent3d{width, height, depth_or_array_layers: 1},
+            TextureDimension::D2,
+            data,
+            format,
+            RenderAssetUsages::default(),
+        )
+    }
+
+    // a 2x2 heightmap over a 2x2 footprint, so the corners are the samples themselves
+    fn baked_terrain(samples: [u8; 4]) -> SdTerrain {
+        let mut images = Assets::<Image>::default();
+        let handle = images.add(heightmap(2, 2, TextureFormat::R8Unorm, samples.to_vec()));
+        let mut terrain = SdTerrain{size: vec2(1.0, 1.0), height: 2.0, heightmap: Some(handle), ..Default::default()};
+        assert!(terrain.bake(&images));
+        terrain
+    }
+
+    #[test]
+    fn heightmap_samples_reads_the_red_channel() {
+        let (size, samples) = heightmap_samples(&heightmap(2, 2, TextureFormat::R8Unorm, vec![0, 255, 51, 102])).unwrap();
+        assert_eq!(size, uvec2(2, 2));
+        assert_eq!(samples, vec![0.0, 1.0, 0.2, 0.4]);
+
+        let rgba = vec![255, 0, 0, 255, 0, 255, 0, 255, 0, 0, 0, 255, 255, 255, 255, 255];
+        let (_, samples) = heightmap_samples(&heightmap(2, 2, TextureFormat::Rgba8Unorm, rgba)).unwrap();
+        assert_eq!(samples, vec![1.0, 0.0, 0.0, 1.0]);
+
+        let r16 = [0u16, 65535, 0, 65535].iter().flat_map(|v| v.to_le_bytes()).collect();
+        let (_, samples) = heightmap_samples(&heightmap(2, 2, TextureFormat::R16Unorm, r16)).unwrap();
+        assert_eq!(samples, vec![0.0, 1.0, 0.0, 1.0]);
+
+        assert!(heightmap_samples(&heightmap(1, 2, TextureFormat::R8Unorm, vec![0, 0])).is_none());
+        assert!(heightmap_samples(&heightmap(2, 2, TextureFormat::Rg16Float, vec![0; 16])).is_none());
+    }
+
+    #[test]
+    fn local_height_is_bilinear_and_clamped_to_the_footprint() {
+        let terrain = baked_terrain([0, 51, 102, 153]); //0.0, 0.2, 0.4, 0.6
+        let close = |a: Option<f32>, b: f32| (a.unwrap() - b).abs() < 1e-5;
+
+        assert!(close(terrain.local_height(vec2(-1.0, -1.0)), 0.0));
+        assert!(close(terrain.local_height(vec2(1.0, -1.0)), 0.4));
+        assert!(close(terrain.local_height(vec2(-1.0, 1.0)), 0.8));
+        assert!(close(terrain.local_height(vec2(1.0, 1.0)), 1.2)); //the far border uses the last cell, not one past it
+        assert!(close(terrain.local_height(vec2(0.0, 0.0)), 0.6));
+        assert!(close(terrain.local_height(vec2(0.5, -1.0)), 0.3));
+
+        assert!(terrain.local_height(vec2(1.01, 0.0)).is_none());
+        assert!(terrain.local_height(vec2(0.0, -1.01)).is_none());
+        assert!(SdTerrain::default().local_height(Vec2::ZERO).is_none()); //not baked yet
+    }
+
+    #[test]
+    fn height_at_goes_through_the_transform() {
+        let terrain = baked_terrain([0, 51, 102, 153]);
+        let transform = GlobalTransform::from(Transform::from_xyz(10.0, 5.0, 0.0).with_scale(vec3(2.0, 3.0, 2.0)));
+
+        let height = terrain.height_at(&transform, vec3(10.0, 100.0, 0.0)).unwrap();
+        assert!((height - (5.0 + 0.6 * 3.0)).abs() < 1e-4);
+        assert!(terrain.height_at(&transform, vec3(12.0, 0.0, 2.0)).is_some());
+        assert!(terrain.height_at(&transform, vec3(12.5, 0.0, 0.0)).is_none());
+    }
+
+    #[test]
+    fn baked_chunks_bound_their_samples() {
+        let mut terrain = SdTerrain{resolution: uvec2(17, 13), chunks: uvec2(4, 3), ..Default::default()};
+        assert!(terrain.bake(&Assets::<Image>::default()));
+
+        let resolution = terrain.baked_resolution;
+        assert_eq!(resolution, uvec2(17, 13));
+        let cell = 2.0 * terrain.size / (resolution - 1).as_vec2();
+        let lowest = terrain.heights.iter().copied().fold(f32::MAX, f32::min);
+        let highest = terrain.heights.iter().copied().fold(f32::MIN, f32::max);
+        assert_eq!(terrain.range, vec2(lowest, highest) * terrain.height);
+
+        let nodes = &terrain.nodes;
+        assert_eq!(nodes.iter().filter(|node| node.child1.x == 1).count(), 12);
+        for node in nodes.iter() {
+            if node.child1.x == 0 {
+                for child in [node.child1, node.child2] {
+                    let child = nodes[child.y as usize].aabb;
+                    assert!(node.aabb.min.cmple(child.min).all() && node.aabb.max.cmpge(child.max).all());
+                }
+                continue;
+            }
+            // every sample that lands in the chunk's footprint has to be inside its box
+            for z in 0..resolution.y {
+                for x in 0..resolution.x {
+                    let xz = vec2(x as f32, z as f32) * cell - terrain.size;
+                    if xz.x < node.aabb.min.x - 1e-4 || xz.x > node.aabb.max.x + 1e-4 || xz.y < node.aabb.min.z - 1e-4 || xz.y > node.aabb.max.z + 1e-4 {
+                        continue;
+                    }
+                    let y = terrain.heights[(z * resolution.x + x) as usize] * terrain.height;
+                    assert!(y > node.aabb.min.y && y < node.aabb.max.y);
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn moving_a_terrain_keeps_its_samples_packed() {
+        let (mut world, mut schedule) = shape_world();
+        let entity = world.spawn((baked_terrain([0, 51, 102, 153]), GlobalTransform::IDENTITY)).id();
+        schedule.run(&mut world);
+        let changed = |world: &World| std::mem::take(&mut *world.resource::<ShapeContainer>().terrain_data_changed.lock().unwrap());
+        assert!(changed(&world));
+
+        *world.get_mut::<GlobalTransform>(entity).unwrap() = GlobalTransform::from_xyz(0.0, 1.0, 0.0);
+        schedule.run(&mut world);
+        assert!(!changed(&world));
+
+        let mut terrain = world.get_mut::<SdTerrain>(entity).unwrap();
+        terrain.heightmap = None;
+        assert!(terrain.bake(&Assets::<Image>::default()));
+        schedule.run(&mut world);
+        assert!(changed(&world));
+    }
+
+    #[test]
+    fn tree_depth_follows_inserts_and_removals() {
+        let (mut world, mut schedule) = shape_world();
+        let mut entities: Vec<Entity> = (0..40).map(|i| {
+            let position = vec3((i * 7 % 13) as f32, (i * 5 % 11) as f32, (i * 3 % 17) as f32) * 2.0;
+            spawn_fog_volume(&mut world, position)
+        }).collect();
+        schedule.run(&mut world);
+        check_tree(&world);
+
+        for entity in entities.iter().step_by(3) {
+            world.despawn(*entity);
+        }
+        entities = entities.into_iter().enumerate().filter(|(i, _)| i % 3 != 0).map(|(_, entity)| entity).collect();
+        schedule.run(&mut world);
+        check_tree(&world);
+
+        // moving everything refits, the shape of the tree stays the same
+        for (i, entity) in entities.iter().enumerate() {
+            *world.get_mut::<GlobalTransform>(*entity).unwrap() = GlobalTransform::from_xyz(i as f32, 0.0, 0.0);
+        }
+        schedule.run(&mut world);
+        check_tree(&world);
+
+        for entity in entities.drain(..) {
+            world.despawn(entity);
+        }
+        schedule.run(&mut world);
+        assert_eq!(check_tree(&world), 0);
+
+        // and an emptied tree takes new leaves again
+        spawn_fog_volume(&mut world, Vec3::ZERO);
+        schedule.run(&mut world);
+        assert_eq!(check_tree(&world), 1);
+        spawn_fog_volume(&mut world, Vec3::X);
+        schedule.run(&mut world);
+        assert_eq!(check_tree(&world), 2);
+    }
+
+    #[test]
+    fn rebuilt_tree_is_balanced() {
+        let (mut world, mut schedule) = shape_world();
+        for i in 0..100 {
+            spawn_fog_volume(&mut world, vec3(i as f32 * 2.0, 0.0, 0.0));
+            schedule.run(&mut world); //one at a time along a line, the worst case for inserting
+            assert!(check_tree(&world) <= MAX_BVH_DEPTH);
+        }
+
+        rebuild_tree(world.resource::<ShapeContainer>(), world.resource::<BvhTree>());
+        assert_eq!(check_tree(&world), 8); //ceil(log2(100)) = 7 levels above the leaves
+    }
+
+    #[test]
+    fn stack_size_covers_the_depth() {
+        for depth in 0..=MAX_BVH_DEPTH * 2 {
+            assert!(bvh_stack_size(depth) > depth);
+            assert_eq!(bvh_stack_size(depth) % 8, 0);
+        }
+    }
+
+    #[test]
+    fn prototype_parts_are_packed_once_with_relative_slots() {
+        let mut assets = Assets::<SdPrototype>::default();
+        let single = assets.add(SdPrototype::Cube(SdCube::default()));
+        let compound = assets.add(SdPrototype::Compound(vec![
+            SdPart{shape: SdPrototype::Sphere(SdSphere::default()), transform: Transform::from_xyz(-1.0, 0.0, 0.0)},
+            SdPart{shape: SdPrototype::Sphere(SdSphere::default()), transform: Transform::from_xyz(1.0, 0.0, 0.0)},
+            SdPart{shape: SdPrototype::Cube(SdCube::default()), transform: Transform::from_xyz(0.0, 2.0, 0.0)},
+        ]));
+        let prototypes: HashMap<_, _> = [&single, &compound].iter()
+            .map(|handle| (handle.id(), Blas::new(assets.get(handle.id()).unwrap())))
+            .collect();
+
+        let parts = PrototypeParts::new(&prototypes);
+        assert!(parts.changed);
+        assert_eq!((parts.spheres.len(), parts.cubes.len()), (2, 2));
+
+        let slot = parts.slots[&single.id()];
+        assert_eq!(slot.x, 2);
+        assert!(slot.y < 2);
+
+        let root = parts.slots[&compound.id()];
+        assert_eq!(root.x, 10);
+        let leaves: Vec<UVec2> = parts.blas_nodes[root.y as usize..].iter().filter(|node| node.child1.x != 0).map(|node| node.child1).collect();
+        assert_eq!(leaves.len(), 3);
+        assert_eq!(leaves.iter().filter(|leaf| leaf.x == 1).count(), 2);
+        assert!(leaves.iter().all(|leaf| leaf.x != 2 || leaf.y != slot.y)); //its cube isn't the single prototype's
+        assert!(leaves.iter().all(|leaf| (leaf.y as usize) < 2));
+    }
+
+    #[test]
+    fn every_preset_marches() {
+        let default = RaymarchSettings::default();
+        let mut medium = default;
+        RaymarchQuality::Medium.apply(&mut medium);
+        assert_eq!((default.max_steps, default.shadow_steps, default.fog_volume_steps), (medium.max_steps, medium.shadow_steps, medium.fog_volume_steps));
+        assert_eq!((default.hit_epsilon, default.normal_epsilon, default.shadow_bias), (medium.hit_epsilon, medium.normal_epsilon, medium.shadow_bias));
+
+        for quality in [RaymarchQuality::Low, RaymarchQuality::Medium, RaymarchQuality::High, RaymarchQuality::Ultra] {
+            let mut settings = RaymarchSettings::default();
+            quality.apply(&mut settings);
+            assert!(settings.max_steps > 0 && settings.shadow_steps > 0 && settings.fog_volume_steps > 0, "{quality:?}");
+            assert!(settings.hit_epsilon > 0.0 && settings.normal_epsilon > 0.0 && settings.shadow_bias > 0.0, "{quality:?}");
+        }
+    }
+}